@@ -0,0 +1,146 @@
+//! Counts the writes a `BoxWriter` makes on its inner writer.
+//!
+//! The "plain" writer only implements `poll_write`, so the default
+//! `poll_write_vectored` hands it just the first slice: a box's head and body
+//! go out in two writes. The "vectored" writer accepts all the slices at once,
+//! the way a socket does with `writev`, so a box goes out in one write.
+//!
+//! Each write carries at most one box, except for the boxes that
+//! `write_bulk` seals up front (with the `rayon` feature), which are handed
+//! to the inner writer up to 32 at a time.
+//!
+//! Run with `cargo bench --bench syscalls`, adding `--features rayon` for
+//! the bulk runs.
+
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_executor::block_on;
+use futures_io::{AsyncWrite, IoSlice};
+use futures_util::io::AsyncWriteExt;
+use ssb_boxstream::BoxWriter;
+use ssb_crypto::secretbox::{Key, Nonce};
+use std::io;
+use std::time::Instant;
+
+trait Counted: AsyncWrite + Unpin + 'static {
+    fn writes(&self) -> usize;
+}
+
+#[derive(Default)]
+struct Plain {
+    writes: usize,
+    bytes: usize,
+}
+
+impl AsyncWrite for Plain {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.writes += 1;
+        self.bytes += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Counted for Plain {
+    fn writes(&self) -> usize {
+        self.writes
+    }
+}
+
+#[derive(Default)]
+struct Vectored {
+    writes: usize,
+    bytes: usize,
+}
+
+impl AsyncWrite for Vectored {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.writes += 1;
+        self.bytes += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        _: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        let n = bufs.iter().map(|b| b.len()).sum();
+        self.writes += 1;
+        self.bytes += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Counted for Vectored {
+    fn writes(&self) -> usize {
+        self.writes
+    }
+}
+
+fn run<W: Counted + Default>(name: &str, bulk: bool, msg_size: usize, count: usize) {
+    let mut w = BoxWriter::new(W::default(), Key([1; 32]), Nonce([2; 24]));
+    let msg = vec![3; msg_size];
+
+    let start = Instant::now();
+    block_on(async {
+        for _ in 0..count {
+            if bulk {
+                #[cfg(feature = "rayon")]
+                w.write_bulk(msg.clone()).await.unwrap();
+            } else {
+                w.write_all(&msg).await.unwrap();
+            }
+            w.flush().await.unwrap();
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let boxes = w.stats().boxes;
+    let inner = w.into_inner();
+    println!(
+        "{:>8}{:>5} {:>6}B x {:<6} boxes: {:>6}  inner writes: {:>6}  writes/box: {:.2}  {:?}",
+        name,
+        if bulk { "bulk" } else { "" },
+        msg_size,
+        count,
+        boxes,
+        inner.writes(),
+        inner.writes() as f64 / boxes as f64,
+        elapsed,
+    );
+}
+
+fn main() {
+    for &(size, count) in &[(32, 10_000), (4096, 2_000), (100_000, 100)] {
+        run::<Plain>("plain", false, size, count);
+        run::<Vectored>("vectored", false, size, count);
+        if cfg!(feature = "rayon") {
+            run::<Plain>("plain", true, size, count);
+            run::<Vectored>("vectored", true, size, count);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Box-stream framing without any IO. Everything in here only needs `core`,
+//! so it's available without the `std` feature.
+
+use crate::bytes::AsBytes;
+use crate::msg::*;
+use crate::NonceGen;
+use core::cmp::min;
+use core::fmt;
+use ssb_crypto::secretbox::{Key, Nonce};
+
+/// The largest box body allowed by the protocol.
+pub const MAX_BOX_SIZE: usize = 4096;
+
+/// The largest box body that the header can describe. Boxes bigger than
+/// [`MAX_BOX_SIZE`](./constant.MAX_BOX_SIZE.html) are only understood by peers
+/// that have agreed to accept them.
+pub const MAX_EXTENDED_BOX_SIZE: usize = u16::MAX as usize;
+
+/// The size of a sealed box header.
+pub const HEADER_SIZE: usize = Head::SIZE;
+
+// A const that fails to evaluate, at compile time, unless `N` is between 1 and `MAX`:
+// `let () = BoxSize::<N, MAX_BOX_SIZE>::CHECK;`
+pub(crate) struct BoxSize<const N: usize, const MAX: usize>;
+
+impl<const N: usize, const MAX: usize> BoxSize<N, MAX> {
+    pub(crate) const CHECK: () = assert!(N > 0 && N <= MAX, "buffer size is out of range");
+}
+
+pub(crate) fn seal(body: &mut [u8], key: &Key, noncegen: &mut NonceGen) -> Head {
+    let head_nonce = noncegen.next();
+    let body_nonce = noncegen.next();
+    seal_with(body, key, head_nonce, body_nonce)
+}
+
+pub(crate) fn seal_with(body: &mut [u8], key: &Key, head_nonce: Nonce, body_nonce: Nonce) -> Head {
+    // The header can't describe a bigger body.
+    assert!(
+        body.len() <= MAX_EXTENDED_BOX_SIZE,
+        "body of {} bytes is too big to seal",
+        body.len()
+    );
+    let body_hmac = key.seal(body, &body_nonce);
+    HeadPayload::new(body.len() as u16, body_hmac).seal(key, head_nonce)
+}
+
+/// Why a box couldn't be opened. Any of these means the stream can't be
+/// trusted from here on, so it shouldn't be read any further.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A header failed authentication: it was sealed with a different key or
+    /// nonce, or changed on the way.
+    HeaderOpenFailed,
+    /// A body failed authentication, or wasn't the length its header gave.
+    BodyOpenFailed,
+    /// A header gave a body bigger than the buffer, or the reader's limit, allows.
+    BodyTooLarge {
+        /// The body size in the header.
+        size: usize,
+        /// The biggest body allowed.
+        max: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::HeaderOpenFailed => f.write_str("Failed to decrypt header"),
+            DecodeError::BodyOpenFailed => f.write_str("Failed to decrypt body"),
+            DecodeError::BodyTooLarge { size, max } => write!(
+                f,
+                "Box body of {} bytes is over the maximum of {}",
+                size, max
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// Seals box bodies in place, and makes the headers that go in front of them.
+pub struct BoxSealer {
+    key: Key,
+    nonces: NonceGen,
+}
+
+impl BoxSealer {
+    pub fn new(key: Key, nonce: Nonce) -> BoxSealer {
+        BoxSealer {
+            key,
+            nonces: NonceGen::with_starting_nonce(nonce),
+        }
+    }
+
+    /// Seal `body` in place, and return the header to send before it.
+    ///
+    /// # Panics
+    /// Panics if `body` is longer than [`MAX_EXTENDED_BOX_SIZE`](./constant.MAX_EXTENDED_BOX_SIZE.html).
+    pub fn seal(&mut self, body: &mut [u8]) -> [u8; HEADER_SIZE] {
+        let head = seal(body, &self.key, &mut self.nonces);
+        let mut out = [0; HEADER_SIZE];
+        out.copy_from_slice(head.as_bytes());
+        out
+    }
+
+    /// The goodbye header, which ends the stream.
+    pub fn goodbye(&mut self) -> [u8; HEADER_SIZE] {
+        let head = HeadPayload::goodbye().seal(&self.key, self.nonces.next());
+        let mut out = [0; HEADER_SIZE];
+        out.copy_from_slice(head.as_bytes());
+        out
+    }
+
+    /// The number of nonces used so far: two for each box, and one for the goodbye.
+    pub fn position(&self) -> u64 {
+        self.nonces.position()
+    }
+}
+
+// For the std reader and writer, which seal and open with the same key and
+// nonces as the codec, but manage their own buffers and headers.
+#[cfg(feature = "std")]
+impl BoxSealer {
+    pub(crate) fn seal_head(&mut self, body: &mut [u8]) -> Head {
+        seal(body, &self.key, &mut self.nonces)
+    }
+
+    pub(crate) fn goodbye_head(&mut self) -> Head {
+        HeadPayload::goodbye().seal(&self.key, self.nonces.next())
+    }
+
+    // The nonces for the next box's header and body, to seal it elsewhere
+    // with `seal_with`.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn next_nonces(&mut self) -> (Nonce, Nonce) {
+        (self.nonces.next(), self.nonces.next())
+    }
+
+    #[cfg(feature = "rayon")]
+    pub(crate) fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+/// An opened box header.
+#[derive(Clone, Copy, Debug)]
+pub struct Header(pub(crate) HeadPayload);
+
+impl Header {
+    /// The size of the body that follows the header.
+    pub fn body_len(&self) -> usize {
+        self.0.body_size.get() as usize
+    }
+}
+
+/// Opens box headers and bodies, in the order they were sealed.
+pub struct BoxOpener {
+    key: Key,
+    nonces: NonceGen,
+}
+
+impl BoxOpener {
+    pub fn new(key: Key, nonce: Nonce) -> BoxOpener {
+        BoxOpener {
+            key,
+            nonces: NonceGen::with_starting_nonce(nonce),
+        }
+    }
+
+    /// Open a box header. Returns `None` for the goodbye header.
+    pub fn open_header(
+        &mut self,
+        header: &[u8; HEADER_SIZE],
+    ) -> Result<Option<Header>, DecodeError> {
+        let mut head = [0; HEADER_SIZE];
+        head.copy_from_slice(header);
+        let hd = match crate::bytes::cast_mut::<Head>(&mut head[..])
+            .open(&self.key, self.nonces.next())
+        {
+            Some(hd) => *hd,
+            None => return Err(DecodeError::HeaderOpenFailed),
+        };
+        if hd.is_goodbye() {
+            Ok(None)
+        } else {
+            Ok(Some(Header(hd)))
+        }
+    }
+
+    /// Open the body that follows `header`, in place.
+    pub fn open_body(&mut self, header: &Header, body: &mut [u8]) -> Result<(), DecodeError> {
+        if body.len() != header.body_len() {
+            return Err(DecodeError::BodyOpenFailed);
+        }
+        open_body_with(&self.key, header, &self.nonces.next(), body)
+    }
+
+    /// The number of nonces used so far: one for each header, and one for each body.
+    pub fn position(&self) -> u64 {
+        self.nonces.position()
+    }
+}
+
+pub(crate) fn open_body_with(
+    key: &Key,
+    header: &Header,
+    nonce: &Nonce,
+    body: &mut [u8],
+) -> Result<(), DecodeError> {
+    if key.open(body, &header.0.body_hmac, nonce) {
+        Ok(())
+    } else {
+        Err(DecodeError::BodyOpenFailed)
+    }
+}
+
+// For the std reader; see `BoxSealer`.
+#[cfg(feature = "std")]
+impl BoxOpener {
+    // The nonce for the next body, to open it elsewhere with `open_body_with`.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn next_nonce(&mut self) -> Nonce {
+        self.nonces.next()
+    }
+
+    #[cfg(feature = "rayon")]
+    pub(crate) fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+/// Turns plaintext into a box-stream, in a buffer of the caller's choosing.
+///
+/// Write plaintext with [`write`](#method.write), and send whatever
+/// [`output`](#method.output) returns, marking it sent with
+/// [`consume`](#method.consume). Boxes are as big as the buffer, up to
+/// [`MAX_BOX_SIZE`](./constant.MAX_BOX_SIZE.html).
+pub struct BoxEncoder<B> {
+    buffer: B,
+    sealer: BoxSealer,
+    box_size: usize,
+    state: EncoderState,
+    closing: bool,
+}
+
+enum EncoderState {
+    Buffering {
+        pos: usize,
+    },
+    // A sealed box, with its body at the start of the buffer.
+    // `pos` counts the bytes of the head and body together.
+    Sealed {
+        head: [u8; HEADER_SIZE],
+        body_size: usize,
+        pos: usize,
+    },
+    Goodbye {
+        head: [u8; HEADER_SIZE],
+        pos: usize,
+    },
+    Closed,
+}
+
+impl<B: AsMut<[u8]>> BoxEncoder<B> {
+    /// # Panics
+    /// Panics if the buffer is empty.
+    pub fn with_buffer(key: Key, nonce: Nonce, mut buffer: B) -> BoxEncoder<B> {
+        let len = buffer.as_mut().len();
+        assert!(len > 0, "buffer is empty");
+        BoxEncoder {
+            buffer,
+            sealer: BoxSealer::new(key, nonce),
+            box_size: min(len, MAX_BOX_SIZE),
+            state: EncoderState::Buffering { pos: 0 },
+            closing: false,
+        }
+    }
+
+    /// Buffer as much of `buf` as fits, and return how much that was.
+    /// Returns 0 while there's output to be sent, or after `close`.
+    pub fn write(&mut self, buf: &[u8]) -> usize {
+        match self.state {
+            EncoderState::Buffering { pos } if !self.closing => {
+                let n = min(self.box_size - pos, buf.len());
+                self.buffer.as_mut()[pos..pos + n].copy_from_slice(&buf[..n]);
+                self.state = EncoderState::Buffering { pos: pos + n };
+                if pos + n == self.box_size {
+                    self.flush();
+                }
+                n
+            }
+            _ => 0,
+        }
+    }
+
+    /// Seal whatever has been buffered into a box, to be sent.
+    pub fn flush(&mut self) {
+        if let EncoderState::Buffering { pos } = self.state {
+            if pos > 0 {
+                let head = self.sealer.seal(&mut self.buffer.as_mut()[..pos]);
+                self.state = EncoderState::Sealed {
+                    head,
+                    body_size: pos,
+                    pos: 0,
+                };
+            }
+        }
+    }
+
+    /// Seal whatever has been buffered, followed by the goodbye.
+    /// No more plaintext is accepted.
+    pub fn close(&mut self) {
+        self.flush();
+        self.closing = true;
+    }
+
+    /// The bytes that should be sent next. Empty when there's nothing to send.
+    /// A box's header and body come out separately.
+    pub fn output(&mut self) -> &[u8] {
+        if let EncoderState::Buffering { .. } = self.state {
+            if self.closing {
+                self.state = EncoderState::Goodbye {
+                    head: self.sealer.goodbye(),
+                    pos: 0,
+                };
+            }
+        }
+        match &self.state {
+            EncoderState::Sealed { head, pos, .. } if *pos < HEADER_SIZE => &head[*pos..],
+            EncoderState::Sealed { body_size, pos, .. } => {
+                &self.buffer.as_mut()[*pos - HEADER_SIZE..*body_size]
+            }
+            EncoderState::Goodbye { head, pos } => &head[*pos..],
+            EncoderState::Buffering { .. } | EncoderState::Closed => &[],
+        }
+    }
+
+    /// Mark `n` bytes of the output as sent.
+    pub fn consume(&mut self, n: usize) {
+        match &mut self.state {
+            EncoderState::Sealed { body_size, pos, .. } => {
+                *pos += n;
+                if *pos >= HEADER_SIZE + *body_size {
+                    self.state = EncoderState::Buffering { pos: 0 };
+                }
+            }
+            EncoderState::Goodbye { pos, .. } => {
+                *pos += n;
+                if *pos >= HEADER_SIZE {
+                    self.state = EncoderState::Closed;
+                }
+            }
+            EncoderState::Buffering { .. } | EncoderState::Closed => {}
+        }
+    }
+
+    /// The number of plaintext bytes waiting to be sealed.
+    pub fn buffered_len(&self) -> usize {
+        match self.state {
+            EncoderState::Buffering { pos } => pos,
+            _ => 0,
+        }
+    }
+
+    /// Whether the goodbye has been sent.
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, EncoderState::Closed)
+    }
+}
+
+impl<const N: usize> BoxEncoder<[u8; N]> {
+    /// Create an encoder with an inline buffer, which makes boxes of up to `N` bytes.
+    /// An `N` of zero or more than 4096 is a compile-time error.
+    pub fn inline(key: Key, nonce: Nonce) -> BoxEncoder<[u8; N]> {
+        let () = BoxSize::<N, MAX_BOX_SIZE>::CHECK;
+        BoxEncoder::with_buffer(key, nonce, [0; N])
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl BoxEncoder<alloc::vec::Vec<u8>> {
+    pub fn new(key: Key, nonce: Nonce) -> BoxEncoder<alloc::vec::Vec<u8>> {
+        BoxEncoder::with_buffer(key, nonce, alloc::vec![0; MAX_BOX_SIZE])
+    }
+}
+
+/// Turns a box-stream back into plaintext, in a buffer of the caller's choosing.
+///
+/// Put ciphertext into [`input_buf`](#method.input_buf) and report how much with
+/// [`filled`](#method.filled), or hand it over with [`push`](#method.push).
+/// Once a box has been opened, its contents are available from
+/// [`plaintext`](#method.plaintext) until they're [`consume`](#method.consume)d.
+/// Bodies can be as big as the buffer.
+pub struct BoxDecoder<B> {
+    buffer: B,
+    opener: BoxOpener,
+    max_body_size: usize,
+    state: DecoderState,
+}
+
+enum DecoderState {
+    Head { head: [u8; HEADER_SIZE], pos: usize },
+    Body { header: Header, pos: usize },
+    Ready { len: usize, pos: usize },
+    Done,
+}
+
+impl<B: AsMut<[u8]>> BoxDecoder<B> {
+    pub fn with_buffer(key: Key, nonce: Nonce, mut buffer: B) -> BoxDecoder<B> {
+        let max_body_size = buffer.as_mut().len();
+        BoxDecoder {
+            buffer,
+            opener: BoxOpener::new(key, nonce),
+            max_body_size,
+            state: DecoderState::Head {
+                head: [0; HEADER_SIZE],
+                pos: 0,
+            },
+        }
+    }
+
+    /// Where the next bytes of ciphertext should go. Empty while there's
+    /// plaintext waiting, or once the goodbye has been received.
+    pub fn input_buf(&mut self) -> &mut [u8] {
+        match &mut self.state {
+            DecoderState::Head { head, pos } => &mut head[*pos..],
+            DecoderState::Body { header, pos } => {
+                &mut self.buffer.as_mut()[*pos..header.body_len()]
+            }
+            DecoderState::Ready { .. } | DecoderState::Done => &mut [],
+        }
+    }
+
+    /// Mark `n` bytes of [`input_buf`](#method.input_buf) as filled, and open the
+    /// header or body if it's complete.
+    pub fn filled(&mut self, n: usize) -> Result<(), DecodeError> {
+        match self.state {
+            DecoderState::Head { head, pos } if pos + n < HEADER_SIZE => {
+                self.state = DecoderState::Head { head, pos: pos + n };
+            }
+            DecoderState::Head { head, .. } => {
+                self.state = match self.opener.open_header(&head)? {
+                    None => DecoderState::Done,
+                    Some(header) if header.body_len() > self.max_body_size => {
+                        return Err(DecodeError::BodyTooLarge {
+                            size: header.body_len(),
+                            max: self.max_body_size,
+                        })
+                    }
+                    Some(header) => DecoderState::Body { header, pos: 0 },
+                };
+                // Empty bodies are complete as soon as they start.
+                self.filled(0)?;
+            }
+            DecoderState::Body { header, pos } if pos + n < header.body_len() => {
+                self.state = DecoderState::Body {
+                    header,
+                    pos: pos + n,
+                };
+            }
+            DecoderState::Body { header, .. } => {
+                let len = header.body_len();
+                self.opener
+                    .open_body(&header, &mut self.buffer.as_mut()[..len])?;
+                self.state = if len == 0 {
+                    DecoderState::Head {
+                        head: [0; HEADER_SIZE],
+                        pos: 0,
+                    }
+                } else {
+                    DecoderState::Ready { len, pos: 0 }
+                };
+            }
+            DecoderState::Ready { .. } | DecoderState::Done => {}
+        }
+        Ok(())
+    }
+
+    /// Take as much of `input` as the decoder can right now, and return how much that was.
+    pub fn push(&mut self, mut input: &[u8]) -> Result<usize, DecodeError> {
+        let mut taken = 0;
+        loop {
+            let buf = self.input_buf();
+            let n = min(buf.len(), input.len());
+            if n == 0 {
+                return Ok(taken);
+            }
+            buf[..n].copy_from_slice(&input[..n]);
+            input = &input[n..];
+            taken += n;
+            self.filled(n)?;
+        }
+    }
+
+    /// Decrypted bytes that haven't been consumed yet.
+    pub fn plaintext(&mut self) -> &[u8] {
+        match self.state {
+            DecoderState::Ready { len, pos } => &self.buffer.as_mut()[pos..len],
+            _ => &[],
+        }
+    }
+
+    /// Mark `n` bytes of plaintext as read.
+    pub fn consume(&mut self, n: usize) {
+        if let DecoderState::Ready { len, pos } = self.state {
+            self.state = if pos + n >= len {
+                DecoderState::Head {
+                    head: [0; HEADER_SIZE],
+                    pos: 0,
+                }
+            } else {
+                DecoderState::Ready { len, pos: pos + n }
+            };
+        }
+    }
+
+    /// Whether the goodbye has been received.
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, DecoderState::Done)
+    }
+}
+
+impl<const N: usize> BoxDecoder<[u8; N]> {
+    /// Create a decoder with an inline buffer, which accepts bodies of up to `N` bytes.
+    /// An `N` of zero or more than 65535 is a compile-time error.
+    pub fn inline(key: Key, nonce: Nonce) -> BoxDecoder<[u8; N]> {
+        let () = BoxSize::<N, MAX_EXTENDED_BOX_SIZE>::CHECK;
+        BoxDecoder::with_buffer(key, nonce, [0; N])
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl BoxDecoder<alloc::vec::Vec<u8>> {
+    pub fn new(key: Key, nonce: Nonce) -> BoxDecoder<alloc::vec::Vec<u8>> {
+        BoxDecoder::with_buffer(key, nonce, alloc::vec![0; MAX_BOX_SIZE])
+    }
+}
@@ -0,0 +1,163 @@
+//! Compression of box bodies, with the `flate2` feature.
+//!
+//! Each body is compressed with raw deflate and flushed, so the reader can
+//! decompress it as soon as it arrives. The deflate stream carries on from one
+//! box to the next, so later boxes can refer back to data in earlier ones.
+//! Like padding, this isn't part of the box-stream protocol: both peers have
+//! to agree to it, and turn it on before the first box.
+//!
+//! Compressing data before encrypting it leaks how well it compresses, through
+//! the size of the boxes. If an attacker can get some of their own data sent
+//! on a stream that also carries secrets, and can see the boxes go by, they
+//! can guess at a secret and check whether the boxes shrink, as in the CRIME
+//! and BREACH attacks. Since the deflate stream carries on across boxes,
+//! attacker data in one box can match a secret sent in any earlier one, not
+//! just in the same box. Padding hides some of this, but not all of it. Only
+//! compress streams where that mix can't happen.
+
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// How a [`BoxWriter`](./struct.BoxWriter.html) compresses the bodies of its boxes.
+/// The peer's reader needs [`set_compressed`](./struct.BoxReader.html#method.set_compressed)
+/// to decompress them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    Off,
+    /// Deflate, at a level from 0 (none) to 9 (best).
+    Deflate(u32),
+}
+
+/// The most that compression can add to a body of `len` bytes.
+pub(crate) fn max_overhead(len: usize) -> usize {
+    // Incompressible data goes in stored blocks, with a few bytes of header
+    // per block, and the flush adds an empty one.
+    len / 4096 + len / 16384 + 24
+}
+
+pub(crate) struct Deflater {
+    compress: Compress,
+}
+
+impl Deflater {
+    pub(crate) fn new(level: u32) -> Deflater {
+        Deflater {
+            compress: Compress::new(flate2::Compression::new(level), false),
+        }
+    }
+
+    /// Compress `body` onto the end of `out`.
+    pub(crate) fn deflate(&mut self, mut body: &[u8], out: &mut Vec<u8>) {
+        out.reserve(body.len() + max_overhead(body.len()));
+        loop {
+            let before = self.compress.total_in();
+            // Only fails on a misuse of flush modes, which never happens here.
+            self.compress
+                .compress_vec(body, out, FlushCompress::Sync)
+                .expect("deflate failed");
+            body = &body[(self.compress.total_in() - before) as usize..];
+            if body.is_empty() && out.len() < out.capacity() {
+                return;
+            }
+            out.reserve(out.capacity());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum InflateError {
+    Corrupt,
+    TooLarge,
+}
+
+pub(crate) struct Inflater {
+    decompress: Decompress,
+    // The plaintext of the current body.
+    pub(crate) out: Vec<u8>,
+}
+
+impl Inflater {
+    pub(crate) fn new() -> Inflater {
+        Inflater {
+            decompress: Decompress::new(false),
+            out: Vec::new(),
+        }
+    }
+
+    /// Decompress `body` into `out`, failing if it comes to more than `max` bytes.
+    pub(crate) fn inflate(&mut self, mut body: &[u8], max: usize) -> Result<usize, InflateError> {
+        self.out.clear();
+        loop {
+            if self.out.len() == self.out.capacity() {
+                // One byte over the limit is enough to know it's too much.
+                let room = max + 1 - self.out.len();
+                self.out.reserve(room.min(self.out.capacity().max(4096)));
+            }
+            let (before_in, before_out) = (self.decompress.total_in(), self.out.len());
+            let status = self
+                .decompress
+                .decompress_vec(body, &mut self.out, FlushDecompress::Sync)
+                .map_err(|_| InflateError::Corrupt)?;
+            let consumed = (self.decompress.total_in() - before_in) as usize;
+            body = &body[consumed..];
+
+            if self.out.len() > max {
+                return Err(InflateError::TooLarge);
+            }
+            if (body.is_empty() && self.out.len() < self.out.capacity())
+                || status == Status::StreamEnd
+            {
+                return Ok(self.out.len());
+            }
+            if consumed == 0 && self.out.len() == before_out {
+                return Err(InflateError::Corrupt);
+            }
+        }
+    }
+}
+
+#[test]
+fn deflate_and_inflate() {
+    let mut deflater = Deflater::new(6);
+    let mut inflater = Inflater::new();
+
+    // Noise that doesn't compress, followed by text that does.
+    let mut x: u32 = 1;
+    let noise: Vec<u8> = (0..65_535)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect();
+    let text = b"{\"type\":\"post\",\"text\":\"hello\"}".repeat(100);
+
+    for len in [1, 100, 4095, 4096, 65_535] {
+        let mut out = vec![];
+        deflater.deflate(&noise[..len], &mut out);
+        assert!(out.len() <= len + max_overhead(len));
+        assert_eq!(inflater.inflate(&out, 100_000).unwrap(), len);
+        assert_eq!(inflater.out, &noise[..len]);
+    }
+    let mut out = vec![];
+    deflater.deflate(&text, &mut out);
+    assert!(out.len() < text.len() / 10);
+    assert_eq!(inflater.inflate(&out, 100_000).unwrap(), text.len());
+    assert_eq!(inflater.out, text);
+
+    // A small body that inflates to too much.
+    let mut deflater = Deflater::new(9);
+    let mut out = vec![];
+    deflater.deflate(&vec![0; 200_000], &mut out);
+    assert!(out.len() < 4096);
+    let mut inflater = Inflater::new();
+    assert!(matches!(
+        inflater.inflate(&out, 100_000),
+        Err(InflateError::TooLarge)
+    ));
+    assert!(matches!(
+        Inflater::new().inflate(&[0xff; 10], 100),
+        Err(InflateError::Corrupt)
+    ));
+}
@@ -1,13 +1,30 @@
-use crate::read::BoxReader;
-use crate::write::BoxWriter;
+#[cfg(feature = "flate2")]
+use crate::compress::Compression;
+use crate::observer::BoxObserver;
+use crate::padding::Padding;
+use crate::pool::{BufferProvider, Pooled};
+use crate::read::{BoxFrame, BoxReader};
+use crate::stats::StreamStats;
+use crate::timer::Timer;
+use crate::write::{BoxWriter, CoverTraffic, FlushPolicy};
+use core::fmt;
 use core::pin::Pin;
 use core::task::{Context, Poll};
-use futures_io::{self as io, AsyncRead, AsyncWrite};
+use futures_io::{self as io, AsyncBufRead, AsyncRead, AsyncWrite, IoSlice, IoSliceMut};
 use ssb_crypto::secretbox::{Key, Nonce};
 
-pub struct BoxStream<R, W> {
-    reader: BoxReader<R, Vec<u8>>,
-    writer: BoxWriter<W, Vec<u8>>,
+pub struct BoxStream<R, W, O = (), B = Vec<u8>> {
+    reader: BoxReader<R, B, O>,
+    writer: BoxWriter<W, B, O>,
+}
+
+impl<R: fmt::Debug, W: fmt::Debug, O, B> fmt::Debug for BoxStream<R, W, O, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BoxStream")
+            .field("reader", &self.reader)
+            .field("writer", &self.writer)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<R, W> BoxStream<R, W>
@@ -23,22 +40,182 @@ where
         w_key: Key,
         w_nonce: Nonce,
     ) -> BoxStream<R, W> {
-        BoxStream {
+        #[allow(unused_mut)]
+        let mut stream = BoxStream {
             reader: BoxReader::new(r, r_key, r_nonce),
             writer: BoxWriter::new(w, w_key, w_nonce),
+        };
+        #[cfg(feature = "tracing")]
+        stream.set_span(tracing::debug_span!("box_stream"));
+        stream
+    }
+}
+
+impl<R, W, P> BoxStream<R, W, (), Pooled<P>>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    P: BufferProvider + Clone + Unpin,
+{
+    /// Like [`new`](#method.new), but the buffers come from `provider`, and are
+    /// only held while a box is being assembled or sent.
+    pub fn pooled(
+        r: R,
+        w: W,
+        r_key: Key,
+        r_nonce: Nonce,
+        w_key: Key,
+        w_nonce: Nonce,
+        provider: P,
+    ) -> BoxStream<R, W, (), Pooled<P>> {
+        #[allow(unused_mut)]
+        let mut stream = BoxStream {
+            reader: BoxReader::pooled(r, r_key, r_nonce, provider.clone()),
+            writer: BoxWriter::pooled(w, w_key, w_nonce, provider),
+        };
+        #[cfg(feature = "tracing")]
+        stream.set_span(tracing::debug_span!("box_stream"));
+        stream
+    }
+}
+
+impl<R, W, O, B> BoxStream<R, W, O, B>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+{
+    /// Replace the observer of both halves of the stream.
+    /// Each half gets its own clone of `observer`.
+    pub fn with_observer<P: BoxObserver + Clone>(self, observer: P) -> BoxStream<R, W, P, B> {
+        BoxStream {
+            reader: self.reader.with_observer(observer.clone()),
+            writer: self.writer.with_observer(observer),
         }
     }
 
-    pub fn split(self) -> (BoxReader<R, Vec<u8>>, BoxWriter<W, Vec<u8>>) {
+    /// Record the events of both halves inside the given span.
+    /// By default, each stream gets its own `box_stream` span.
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: tracing::Span) {
+        self.reader.set_span(span.clone());
+        self.writer.set_span(span);
+    }
+
+    /// See [`BoxReader::set_read_ahead`](./struct.BoxReader.html#method.set_read_ahead).
+    pub fn set_read_ahead(&mut self, size: usize) {
+        self.reader.set_read_ahead(size)
+    }
+
+    /// See [`BoxReader::set_max_body_size`](./struct.BoxReader.html#method.set_max_body_size).
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.reader.set_max_body_size(size)
+    }
+
+    /// See [`BoxWriter::set_box_size`](./struct.BoxWriter.html#method.set_box_size).
+    pub fn set_box_size(&mut self, size: usize) {
+        self.writer.set_box_size(size)
+    }
+
+    /// Pad the bodies of outgoing boxes, and strip the padding from incoming ones.
+    /// The peer has to do the same; see [`Padding`](./enum.Padding.html).
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.writer.set_padding(padding);
+        self.reader.set_padded(padding != Padding::Off);
+    }
+
+    /// Compress the bodies of outgoing boxes, and decompress incoming ones.
+    /// The peer has to do the same; see [`Compression`](./enum.Compression.html).
+    #[cfg(feature = "flate2")]
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.writer.set_compression(compression);
+        self.reader.set_compressed(compression != Compression::Off);
+    }
+
+    /// See [`BoxReader::available`](./struct.BoxReader.html#method.available).
+    pub fn available(&self) -> usize {
+        self.reader.available()
+    }
+
+    /// See [`BoxWriter::buffered_len`](./struct.BoxWriter.html#method.buffered_len).
+    pub fn buffered_len(&self) -> usize {
+        self.writer.buffered_len()
+    }
+
+    /// See [`BoxWriter::pending_ciphertext_len`](./struct.BoxWriter.html#method.pending_ciphertext_len).
+    pub fn pending_ciphertext_len(&self) -> usize {
+        self.writer.pending_ciphertext_len()
+    }
+
+    /// See [`BoxWriter::set_flush_policy`](./struct.BoxWriter.html#method.set_flush_policy).
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.writer.set_flush_policy(policy)
+    }
+
+    /// See [`BoxWriter::set_timer`](./struct.BoxWriter.html#method.set_timer).
+    pub fn set_timer<T: Timer + Send + Sync + 'static>(&mut self, timer: T) {
+        self.writer.set_timer(timer)
+    }
+
+    /// See [`BoxWriter::poll_auto_flush`](./struct.BoxWriter.html#method.poll_auto_flush).
+    pub fn poll_auto_flush(&mut self, cx: &mut Context) -> Poll<Result<(), io::Error>>
+    where
+        O: BoxObserver + Unpin,
+    {
+        self.writer.poll_auto_flush(cx)
+    }
+
+    /// See [`BoxWriter::set_cover_traffic`](./struct.BoxWriter.html#method.set_cover_traffic).
+    /// The peer's reader skips cover boxes, so it needn't do anything to receive them.
+    pub fn set_cover_traffic(&mut self, policy: CoverTraffic) {
+        self.writer.set_cover_traffic(policy)
+    }
+
+    /// See [`BoxWriter::poll_cover_traffic`](./struct.BoxWriter.html#method.poll_cover_traffic).
+    pub fn poll_cover_traffic(&mut self, cx: &mut Context) -> Poll<Result<(), io::Error>>
+    where
+        O: BoxObserver + Unpin,
+    {
+        self.writer.poll_cover_traffic(cx)
+    }
+
+    /// See [`BoxReader::poll_has_data`](./struct.BoxReader.html#method.poll_has_data).
+    pub fn poll_has_data(&mut self, cx: &mut Context) -> Poll<Result<bool, io::Error>>
+    where
+        O: BoxObserver + Unpin,
+    {
+        self.reader.poll_has_data(cx)
+    }
+
+    /// See [`BoxReader::poll_read_box`](./struct.BoxReader.html#method.poll_read_box).
+    pub fn poll_read_box(&mut self, cx: &mut Context) -> Poll<Result<BoxFrame<'_>, io::Error>>
+    where
+        O: BoxObserver + Unpin,
+    {
+        self.reader.poll_read_box(cx)
+    }
+
+    /// Snapshot of the traffic in both directions.
+    pub fn stats(&self) -> StreamStats {
+        StreamStats {
+            read: self.reader.stats(),
+            write: self.writer.stats(),
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn split(self) -> (BoxReader<R, B, O>, BoxWriter<W, B, O>) {
         let BoxStream { reader, writer } = self;
         (reader, writer)
     }
 }
 
-impl<R, W> AsyncRead for BoxStream<R, W>
+impl<R, W, O, B> AsyncRead for BoxStream<R, W, O, B>
 where
     R: Unpin + AsyncRead + 'static,
     W: Unpin + AsyncWrite + 'static,
+    O: BoxObserver + Unpin,
+    B: AsMut<[u8]> + Unpin,
 {
     fn poll_read(
         mut self: Pin<&mut Self>,
@@ -47,12 +224,38 @@ where
     ) -> Poll<Result<usize, io::Error>> {
         Pin::new(&mut self.reader).poll_read(cx, buf)
     }
+
+    fn poll_read_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.reader).poll_read_vectored(cx, bufs)
+    }
 }
 
-impl<R, W> AsyncWrite for BoxStream<R, W>
+impl<R, W, O, B> AsyncBufRead for BoxStream<R, W, O, B>
 where
     R: Unpin + AsyncRead + 'static,
     W: Unpin + AsyncWrite + 'static,
+    O: BoxObserver + Unpin,
+    B: AsMut<[u8]> + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<&[u8], io::Error>> {
+        Pin::new(&mut self.get_mut().reader).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.reader).consume(amt)
+    }
+}
+
+impl<R, W, O, B> AsyncWrite for BoxStream<R, W, O, B>
+where
+    R: Unpin + AsyncRead + 'static,
+    W: Unpin + AsyncWrite + 'static,
+    O: BoxObserver + Unpin,
+    B: AsMut<[u8]> + Unpin,
 {
     fn poll_write(
         mut self: Pin<&mut Self>,
@@ -62,6 +265,14 @@ where
         Pin::new(&mut self.writer).poll_write(cx, buf)
     }
 
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.writer).poll_write_vectored(cx, bufs)
+    }
+
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
         Pin::new(&mut self.writer).poll_flush(cx)
     }
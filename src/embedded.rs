@@ -0,0 +1,298 @@
+//! Box-stream over the [`embedded-io`](https://docs.rs/embedded-io) and
+//! [`embedded-io-async`](https://docs.rs/embedded-io-async) traits.
+//!
+//! [`BoxReader`](./struct.BoxReader.html) and [`BoxWriter`](./struct.BoxWriter.html)
+//! work with any buffer, such as a `[u8; 4096]`, so they don't need to allocate.
+//! They implement the blocking traits when the inner reader or writer does
+//! (with the `embedded-io` feature), and the async traits likewise
+//! (with the `embedded-io-async` feature).
+
+use crate::codec::{BoxDecoder, BoxEncoder, DecodeError};
+use core::fmt;
+use ssb_crypto::secretbox::{Key, Nonce};
+
+use eio::{ErrorKind, ErrorType};
+// The error traits are the same in both crates.
+#[cfg(feature = "embedded-io")]
+use embedded_io as eio;
+#[cfg(not(feature = "embedded-io"))]
+use embedded_io_async as eio;
+
+/// An error from an embedded box-stream reader or writer.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The inner reader or writer failed.
+    Io(E),
+    /// A box couldn't be opened.
+    Decode(DecodeError),
+    /// The inner reader ended before the goodbye.
+    UnexpectedEof,
+    /// The inner writer stopped accepting bytes.
+    WriteZero,
+}
+
+impl<E> From<DecodeError> for Error<E> {
+    fn from(err: DecodeError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "IO error: {:?}", err),
+            Error::Decode(err) => err.fmt(f),
+            Error::UnexpectedEof => f.write_str("Stream ended before the goodbye"),
+            Error::WriteZero => f.write_str("Inner writer accepted no bytes"),
+        }
+    }
+}
+
+impl<E: eio::Error> eio::Error for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(err) => err.kind(),
+            Error::Decode(_) => ErrorKind::InvalidData,
+            Error::UnexpectedEof => ErrorKind::Other,
+            Error::WriteZero => ErrorKind::WriteZero,
+        }
+    }
+}
+
+pub struct BoxReader<R, B> {
+    inner: R,
+    decoder: BoxDecoder<B>,
+}
+
+impl<R, B: AsMut<[u8]>> BoxReader<R, B> {
+    /// Create a reader that opens boxes in `buffer`. To accept every box
+    /// allowed by the protocol, the buffer needs to be 4096 bytes.
+    pub fn new(inner: R, key: Key, nonce: Nonce, buffer: B) -> BoxReader<R, B> {
+        BoxReader {
+            inner,
+            decoder: BoxDecoder::with_buffer(key, nonce, buffer),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.decoder.is_closed()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Copy out any decrypted bytes that are waiting.
+    fn take_plaintext(&mut self, buf: &mut [u8]) -> usize {
+        let plain = self.decoder.plaintext();
+        let n = plain.len().min(buf.len());
+        buf[..n].copy_from_slice(&plain[..n]);
+        self.decoder.consume(n);
+        n
+    }
+
+    // Account for `n` bytes read from the inner reader.
+    fn filled<E>(&mut self, n: usize) -> Result<(), Error<E>> {
+        if n == 0 {
+            return Err(Error::UnexpectedEof);
+        }
+        self.decoder.filled(n)?;
+        Ok(())
+    }
+}
+
+impl<R, const N: usize> BoxReader<R, [u8; N]> {
+    /// Create a reader with an inline buffer; see
+    /// [`crate::BoxReader::inline`](../struct.BoxReader.html#method.inline).
+    pub fn inline(inner: R, key: Key, nonce: Nonce) -> BoxReader<R, [u8; N]> {
+        BoxReader {
+            inner,
+            decoder: BoxDecoder::inline(key, nonce),
+        }
+    }
+}
+
+impl<R: ErrorType, B> ErrorType for BoxReader<R, B> {
+    type Error = Error<R::Error>;
+}
+
+#[cfg(feature = "embedded-io")]
+impl<R: embedded_io::Read, B: AsMut<[u8]>> embedded_io::Read for BoxReader<R, B> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        while !buf.is_empty() && !self.decoder.is_closed() {
+            let n = self.take_plaintext(buf);
+            if n > 0 {
+                return Ok(n);
+            }
+            let n = self
+                .inner
+                .read(self.decoder.input_buf())
+                .map_err(Error::Io)?;
+            self.filled(n)?;
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<R: embedded_io_async::Read, B: AsMut<[u8]>> embedded_io_async::Read for BoxReader<R, B> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        while !buf.is_empty() && !self.decoder.is_closed() {
+            let n = self.take_plaintext(buf);
+            if n > 0 {
+                return Ok(n);
+            }
+            let n = self
+                .inner
+                .read(self.decoder.input_buf())
+                .await
+                .map_err(Error::Io)?;
+            self.filled(n)?;
+        }
+        Ok(0)
+    }
+}
+
+pub struct BoxWriter<W, B> {
+    inner: W,
+    encoder: BoxEncoder<B>,
+    closing: bool,
+}
+
+impl<W, B: AsMut<[u8]>> BoxWriter<W, B> {
+    /// Create a writer that buffers plaintext in `buffer`. Boxes are as big
+    /// as the buffer, up to [`MAX_BOX_SIZE`](../constant.MAX_BOX_SIZE.html).
+    pub fn new(inner: W, key: Key, nonce: Nonce, buffer: B) -> BoxWriter<W, B> {
+        BoxWriter {
+            inner,
+            encoder: BoxEncoder::with_buffer(key, nonce, buffer),
+            closing: false,
+        }
+    }
+
+    /// End the stream. Whatever is buffered is sealed, and followed by the
+    /// goodbye; both are sent by the next `flush`. Writes after this fail
+    /// with [`Error::WriteZero`](./enum.Error.html#variant.WriteZero).
+    pub fn close(&mut self) {
+        self.encoder.close();
+        self.closing = true;
+    }
+
+    /// Whether the goodbye has been sent.
+    pub fn is_closed(&self) -> bool {
+        self.encoder.is_closed()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    // The traits don't allow `Ok(0)` for a non-empty `buf`, so a write
+    // after `close` is an error. It doesn't send anything.
+    fn check_open<E>(&self) -> Result<(), Error<E>> {
+        if self.closing {
+            return Err(Error::WriteZero);
+        }
+        Ok(())
+    }
+
+    // Account for `n` bytes taken by the inner writer.
+    fn sent<E>(&mut self, n: usize) -> Result<(), Error<E>> {
+        if n == 0 {
+            return Err(Error::WriteZero);
+        }
+        self.encoder.consume(n);
+        Ok(())
+    }
+}
+
+impl<W, const N: usize> BoxWriter<W, [u8; N]> {
+    /// Create a writer with an inline buffer, which sends boxes of up to `N` bytes.
+    /// An `N` of zero or more than 4096 is a compile-time error.
+    pub fn inline(inner: W, key: Key, nonce: Nonce) -> BoxWriter<W, [u8; N]> {
+        BoxWriter {
+            inner,
+            encoder: BoxEncoder::inline(key, nonce),
+            closing: false,
+        }
+    }
+}
+
+impl<W: ErrorType, B> ErrorType for BoxWriter<W, B> {
+    type Error = Error<W::Error>;
+}
+
+#[cfg(feature = "embedded-io")]
+impl<W: embedded_io::Write, B: AsMut<[u8]>> BoxWriter<W, B> {
+    fn send_output(&mut self) -> Result<(), Error<W::Error>> {
+        loop {
+            let out = self.encoder.output();
+            if out.is_empty() {
+                return Ok(());
+            }
+            let n = self.inner.write(out).map_err(Error::Io)?;
+            self.sent(n)?;
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<W: embedded_io::Write, B: AsMut<[u8]>> embedded_io::Write for BoxWriter<W, B> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.check_open()?;
+        loop {
+            // Nothing is taken while a box is waiting to be sent.
+            let n = self.encoder.write(buf);
+            if n > 0 {
+                return Ok(n);
+            }
+            self.send_output()?;
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.encoder.flush();
+        self.send_output()?;
+        self.inner.flush().map_err(Error::Io)
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<W: embedded_io_async::Write, B: AsMut<[u8]>> BoxWriter<W, B> {
+    async fn send_output_async(&mut self) -> Result<(), Error<W::Error>> {
+        loop {
+            let out = self.encoder.output();
+            if out.is_empty() {
+                return Ok(());
+            }
+            let n = self.inner.write(out).await.map_err(Error::Io)?;
+            self.sent(n)?;
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<W: embedded_io_async::Write, B: AsMut<[u8]>> embedded_io_async::Write for BoxWriter<W, B> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.check_open()?;
+        loop {
+            let n = self.encoder.write(buf);
+            if n > 0 {
+                return Ok(n);
+            }
+            self.send_output_async().await?;
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.encoder.flush();
+        self.send_output_async().await?;
+        self.inner.flush().await.map_err(Error::Io)
+    }
+}
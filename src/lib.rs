@@ -1,30 +1,86 @@
+//! Without the default `std` feature, only the IO-free framing in
+//! [`BoxEncoder`](./struct.BoxEncoder.html) and [`BoxDecoder`](./struct.BoxDecoder.html)
+//! is available, and the crate is `no_std`. The `alloc` feature adds constructors
+//! that allocate their own buffers.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[macro_use]
+#[cfg_attr(not(feature = "std"), allow(unused_macros))]
+mod trace;
+
 mod bytes;
+mod codec;
+#[cfg(all(feature = "std", feature = "flate2"))]
+mod compress;
+#[cfg(feature = "std")]
 mod duplex;
+#[cfg(any(feature = "embedded-io", feature = "embedded-io-async"))]
+pub mod embedded;
 mod msg;
 mod noncegen;
 use noncegen::*;
+mod observer;
+#[cfg(feature = "std")]
+mod padding;
+#[cfg(feature = "std")]
+mod pool;
+#[cfg(feature = "std")]
 mod read;
+#[cfg(feature = "std")]
+mod readahead;
+mod stats;
+#[cfg(feature = "std")]
+mod timer;
+#[cfg(feature = "std")]
 mod write;
 
+pub use codec::*;
+#[cfg(all(feature = "std", feature = "flate2"))]
+pub use compress::Compression;
+#[cfg(feature = "std")]
 pub use duplex::*;
+pub use observer::*;
+#[cfg(feature = "std")]
+pub use padding::Padding;
+#[cfg(feature = "std")]
+pub use pool::*;
+#[cfg(feature = "std")]
 pub use read::*;
+pub use stats::*;
+#[cfg(feature = "std")]
+pub use timer::*;
+#[cfg(feature = "std")]
 pub use write::*;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use crate::bytes::AsBytes;
+    use crate::codec::*;
+    #[cfg(feature = "flate2")]
+    use crate::compress::*;
+    use crate::duplex::*;
     use crate::msg::*;
+    use crate::padding::*;
+    use crate::pool::*;
     use crate::read::*;
+    use crate::stats::*;
+    use crate::timer::*;
     use crate::write::*;
     use crate::NonceGen;
 
     use core::pin::Pin;
-    use core::task::Context;
+    use core::task::{Context, Poll};
+    use core::time::Duration;
     use futures_executor::block_on;
-    use futures_io::AsyncRead;
+    use futures_io::{AsyncRead, AsyncWrite};
     use futures_task::noop_waker;
-    use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+    use futures_util::io::{AsyncReadExt, AsyncWriteExt, Cursor};
     use ssb_crypto::secretbox::{Key, Nonce};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
 
     // Test data from https://github.com/AljoschaMeyer/box-stream-c
     const KEY: Key = Key([
@@ -145,6 +201,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unused_io_amount)]
     fn big_body() {
         let (rbw, rbr) = async_ringbuffer::ring_buffer(16_384);
         let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
@@ -164,4 +221,1431 @@ mod tests {
             boxw.close().await.unwrap();
         });
     }
+
+    #[test]
+    fn debug_is_redacted() {
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            boxw.write_all(&[0, 1, 2, 3, 4, 5, 6, 7]).await.unwrap();
+            boxw.flush().await.unwrap();
+            boxw.write_all(&[8, 9]).await.unwrap();
+        });
+        let w = format!("{:?}", boxw);
+
+        let wire = boxw.into_inner().into_inner();
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            let mut buf = [0; 3];
+            boxr.read_exact(&mut buf).await.unwrap();
+        });
+        let r = format!("{:?}", boxr);
+
+        assert!(w.contains("Buffering { pos: 2 }"), "{}", w);
+        assert!(w.contains("boxes: 1"), "{}", w);
+        assert!(w.contains("failed: false"), "{}", w);
+        assert!(r.contains("Ready { body_size: 8, pos: 3 }"), "{}", r);
+        assert!(r.contains("failed: false"), "{}", r);
+
+        for s in &[w, r] {
+            assert!(!s.contains("key"));
+            assert!(!s.contains("nonce"));
+            assert!(!s.contains(&format!("{:?}", &KEY.0[..4])[1..]));
+        }
+
+        let mut head = HeadPayload::new(8, ssb_crypto::secretbox::Hmac([7; 16]));
+        assert_eq!(
+            format!("{:?}", head),
+            "HeadPayload { body_size: 8, goodbye: false, .. }"
+        );
+        head = HeadPayload::goodbye();
+        assert_eq!(
+            format!("{:?}", head.seal(&KEY, Nonce(NONCE_BYTES))),
+            "Head { .. }"
+        );
+
+        let mut boxw = BoxWriter::new(FailFlush(true), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(boxw.flush()).unwrap_err();
+        let w = format!("{:?}", boxw);
+        assert!(w.contains("failed: true"), "{}", w);
+
+        let stream = BoxStream::new(
+            Cursor::new(vec![]),
+            Cursor::new(vec![]),
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+        );
+        assert!(format!("{:?}", stream).ends_with(", .. }"));
+    }
+
+    #[test]
+    fn stats() {
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(16_384);
+        let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+
+        block_on(async {
+            boxw.write_all(&[1; 5000]).await.unwrap();
+            boxw.flush().await.unwrap();
+            boxw.close().await.unwrap();
+
+            let mut buf = vec![];
+            boxr.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf.len(), 5000);
+        });
+
+        let w = boxw.stats();
+        assert_eq!(w.boxes, 2);
+        assert_eq!(w.plaintext_bytes, 5000);
+        assert_eq!(w.ciphertext_bytes, 5000 + 3 * Head::SIZE as u64);
+        assert_eq!(w.flushes, 2);
+        assert_eq!(w.body_sizes.counts()[6], 1);
+        assert_eq!(w.body_sizes.counts()[8], 1);
+        assert!(w.goodbye);
+
+        let r = boxr.stats();
+        assert_eq!(r.flushes, 0);
+        assert_eq!(Stats { flushes: 0, ..w }, r);
+        assert!(!r.truncated);
+
+        // A stream that ends before the goodbye.
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(boxw.write_all(&[1; 100])).unwrap();
+        block_on(boxw.flush()).unwrap();
+        let wire = boxw.into_inner().into_inner();
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        let err = block_on(boxr.read_to_end(&mut vec![])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        let r = boxr.stats();
+        assert_eq!(r.boxes, 1);
+        assert!(r.truncated);
+        assert!(!r.goodbye);
+    }
+
+    #[test]
+    fn observer() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Default)]
+        struct Events(RefCell<Vec<String>>);
+        impl crate::BoxObserver for Events {
+            fn on_box_opened(&self, index: u64, len: usize) {
+                self.0
+                    .borrow_mut()
+                    .push(format!("opened {} {}", index, len));
+            }
+            fn on_box_sealed(&self, index: u64, len: usize) {
+                self.0
+                    .borrow_mut()
+                    .push(format!("sealed {} {}", index, len));
+            }
+            fn on_goodbye_received(&self) {
+                self.0.borrow_mut().push("goodbye received".into());
+            }
+            fn on_goodbye_sent(&self) {
+                self.0.borrow_mut().push("goodbye sent".into());
+            }
+        }
+
+        let events = Rc::new(Events::default());
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut boxw =
+            BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES)).with_observer(events.clone());
+        let mut boxr =
+            BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES)).with_observer(events.clone());
+
+        block_on(async {
+            boxw.write_all(&[1, 2, 3]).await.unwrap();
+            boxw.flush().await.unwrap();
+            boxw.close().await.unwrap();
+
+            let mut buf = vec![];
+            boxr.read_to_end(&mut buf).await.unwrap();
+        });
+
+        assert_eq!(
+            &*events.0.borrow(),
+            &[
+                "sealed 0 3",
+                "goodbye sent",
+                "opened 0 3",
+                "goodbye received"
+            ]
+        );
+    }
+
+    #[test]
+    fn backpressure_accessors() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(40);
+        let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+
+        block_on(boxw.write_all(&[0, 1, 2, 3, 4, 5, 6, 7])).unwrap();
+        assert_eq!(boxw.buffered_len(), 8);
+        assert_eq!(boxw.pending_ciphertext_len(), 0);
+
+        // Only 40 of the 42 bytes fit in the ring buffer.
+        assert!(Pin::new(&mut boxw).poll_flush(&mut cx).is_pending());
+        assert_eq!(boxw.buffered_len(), 0);
+        assert_eq!(boxw.pending_ciphertext_len(), 2);
+
+        assert_eq!(boxr.available(), 0);
+        assert!(boxr.poll_has_data(&mut cx).is_pending());
+
+        block_on(boxw.flush()).unwrap();
+        assert_eq!(boxw.pending_ciphertext_len(), 0);
+
+        match boxr.poll_has_data(&mut cx) {
+            Poll::Ready(Ok(true)) => {}
+            p => panic!("{:?}", p),
+        }
+        assert_eq!(boxr.available(), 8);
+
+        let mut buf = [0; 3];
+        block_on(boxr.read_exact(&mut buf)).unwrap();
+        assert_eq!(boxr.available(), 5);
+    }
+
+    #[test]
+    fn empty_box_is_not_eof() {
+        let (mut rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut noncegen = NonceGen::with_starting_nonce(Nonce(NONCE_BYTES));
+
+        block_on(async {
+            let head = seal(&mut [], &KEY, &mut noncegen);
+            rbw.write_all(head.as_bytes()).await.unwrap();
+
+            let mut body = [1, 2];
+            let head = seal(&mut body, &KEY, &mut noncegen);
+            rbw.write_all(head.as_bytes()).await.unwrap();
+            rbw.write_all(&body).await.unwrap();
+
+            let mut buf = [0; 2];
+            assert_eq!(boxr.read(&mut buf).await.unwrap(), 2);
+            assert_eq!(buf, [1, 2]);
+            assert_eq!(boxr.stats().boxes, 2);
+        });
+    }
+
+    #[test]
+    fn eof_before_goodbye() {
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            boxw.write_all(&[1; 8]).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        let wire = boxw.into_inner().into_inner();
+
+        // Cut off partway through a head, and partway through a body.
+        for len in [10, Head::SIZE + 4] {
+            let inner = Cursor::new(wire[..len].to_vec());
+            let mut boxr = BoxReader::new(inner, KEY.clone(), Nonce(NONCE_BYTES));
+            let err = block_on(boxr.read(&mut [0; 8])).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            assert!(boxr.stats().truncated);
+        }
+    }
+
+    struct CountReads<R> {
+        inner: R,
+        reads: usize,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for CountReads<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.reads += 1;
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    fn small_boxes(count: usize) -> Vec<u8> {
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            for i in 0..count {
+                boxw.write_all(&[i as u8; 10]).await.unwrap();
+                boxw.flush().await.unwrap();
+            }
+            boxw.close().await.unwrap();
+        });
+        boxw.into_inner().into_inner()
+    }
+
+    #[test]
+    fn read_ahead() {
+        let wire = small_boxes(20);
+        for &(read_ahead, reads) in &[(0, 41), (8192, 1), (100, 10)] {
+            let inner = CountReads {
+                inner: Cursor::new(wire.clone()),
+                reads: 0,
+            };
+            let mut boxr = BoxReader::new(inner, KEY.clone(), Nonce(NONCE_BYTES));
+            boxr.set_read_ahead(read_ahead);
+
+            let mut buf = vec![];
+            block_on(boxr.read_to_end(&mut buf)).unwrap();
+            assert_eq!(buf.len(), 200);
+            assert_eq!(buf[190..], [19; 10]);
+            assert_eq!(boxr.into_inner().reads, reads, "read-ahead {}", read_ahead);
+        }
+    }
+
+    // Returns at most `chunk` bytes per read.
+    struct Trickle<R> {
+        inner: R,
+        chunk: usize,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for Trickle<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let n = buf.len().min(self.chunk);
+            Pin::new(&mut self.inner).poll_read(cx, &mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn read_into_large_buffer() {
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        let body: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        block_on(async {
+            boxw.write_all(&body).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        let wire = boxw.into_inner().into_inner();
+
+        for &chunk in &[3, 4096, 100_000] {
+            let inner = Trickle {
+                inner: Cursor::new(wire.clone()),
+                chunk,
+            };
+            let mut boxr = BoxReader::new(inner, KEY.clone(), Nonce(NONCE_BYTES));
+            let mut out = vec![];
+            let mut buf = vec![0; 65536];
+            block_on(async {
+                loop {
+                    let n = boxr.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    out.extend_from_slice(&buf[..n]);
+                }
+            });
+            assert_eq!(out, body);
+            assert_eq!(boxr.stats().boxes, 3);
+        }
+        // A body that fails to open isn't left in the caller's buffer.
+        let mut wire = wire;
+        wire[Head::SIZE] ^= 1;
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        let mut buf = vec![0xaa; 65536];
+        assert!(block_on(boxr.read(&mut buf)).is_err());
+        assert!(buf[..MAX_BOX_SIZE].iter().all(|b| *b == 0));
+        assert!(buf[MAX_BOX_SIZE..].iter().all(|b| *b == 0xaa));
+    }
+
+    #[test]
+    fn buf_read_lines() {
+        use futures_util::io::AsyncBufReadExt;
+
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+
+        block_on(async {
+            boxw.write_all(b"hello\nwor").await.unwrap();
+            boxw.flush().await.unwrap();
+            boxw.write_all(b"ld\n").await.unwrap();
+            boxw.close().await.unwrap();
+
+            assert_eq!(boxr.fill_buf().await.unwrap(), b"hello\nwor");
+            boxr.consume_unpin(2);
+            assert_eq!(boxr.fill_buf().await.unwrap(), b"llo\nwor");
+
+            let mut line = String::new();
+            boxr.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "llo\n");
+            line.clear();
+            boxr.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "world\n");
+            assert_eq!(boxr.fill_buf().await.unwrap(), b"");
+            assert!(boxr.is_closed());
+        });
+    }
+
+    #[test]
+    fn vectored() {
+        use futures_io::{IoSlice, IoSliceMut};
+
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(16_384);
+        let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+
+        block_on(async {
+            let bufs = [
+                IoSlice::new(b"head"),
+                IoSlice::new(&[]),
+                IoSlice::new(b"payload"),
+            ];
+            assert_eq!(boxw.write_vectored(&bufs).await.unwrap(), 11);
+            assert_eq!(boxw.buffered_len(), 11);
+            boxw.flush().await.unwrap();
+
+            // Fill up one box and spill into the next.
+            let big = [7; 4000];
+            let bufs = [IoSlice::new(&big), IoSlice::new(&big)];
+            assert_eq!(boxw.write_vectored(&bufs).await.unwrap(), 8000);
+            assert_eq!(boxw.stats().boxes, 2);
+            assert_eq!(boxw.buffered_len(), 8000 - 4096);
+            boxw.flush().await.unwrap();
+
+            let (mut a, mut b) = ([0; 4], [0; 20]);
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+            assert_eq!(boxr.read_vectored(&mut bufs).await.unwrap(), 11);
+            assert_eq!(&a, b"head");
+            assert_eq!(&b[..7], b"payload");
+
+            let mut c = vec![0; 8000];
+            boxr.read_exact(&mut c).await.unwrap();
+            assert!(c.iter().all(|&x| x == 7));
+        });
+    }
+
+    #[test]
+    fn write_owned() {
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+
+        let body: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let expected = [&[1, 2, 3][..], &body].concat();
+
+        let write = async {
+            boxw.write_all(&[1, 2, 3]).await.unwrap();
+            boxw.write_owned(body).await.unwrap();
+            boxw.close().await.unwrap();
+        };
+        let read = async {
+            let mut buf = vec![];
+            boxr.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let ((), got) = block_on(futures_util::future::join(write, read));
+        assert_eq!(got, expected);
+
+        let sizes = boxw.stats().body_sizes;
+        assert_eq!(boxw.stats().boxes, 4);
+        assert_eq!(sizes.counts()[0], 1);
+        assert_eq!(sizes.counts()[7], 1);
+        assert_eq!(sizes.counts()[8], 2);
+    }
+
+    #[test]
+    fn box_size() {
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        boxw.set_box_size(100);
+        block_on(async {
+            boxw.write_all(&[7; 250]).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        assert_eq!(boxw.stats().boxes, 3);
+        assert_eq!(boxw.stats().body_sizes.counts()[3], 2);
+
+        let body: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        let mut boxw =
+            BoxWriter::with_box_size(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES), 10_000);
+        assert!(!boxw.is_strict());
+        block_on(async {
+            boxw.write_all(&body).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        assert_eq!(boxw.stats().boxes, 2);
+        let wire = boxw.into_inner().into_inner();
+
+        // A reader with the default limit refuses the big boxes.
+        let mut boxr = BoxReader::new(Cursor::new(wire.clone()), KEY.clone(), Nonce(NONCE_BYTES));
+        let err = block_on(boxr.read_to_end(&mut vec![])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(boxr.stats().ciphertext_bytes, 34);
+
+        let mut boxr = BoxReader::with_max_body_size(
+            Cursor::new(wire),
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+            MAX_EXTENDED_BOX_SIZE,
+        );
+        let mut got = vec![];
+        block_on(boxr.read_to_end(&mut got)).unwrap();
+        assert_eq!(got, body);
+
+        // A bigger buffer only makes bigger boxes once it's asked for.
+        let mut boxw = BoxWriter::with_buffer(
+            Vec::<u8>::new(),
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+            vec![0; 10_000],
+        );
+        assert_eq!(boxw.box_size(), MAX_BOX_SIZE);
+        boxw.set_strict(false);
+        boxw.set_box_size(10_000);
+        assert_eq!(boxw.box_size(), 10_000);
+    }
+
+    #[test]
+    fn codec() {
+        let body: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            boxw.write_all(&body).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        let expected = boxw.into_inner().into_inner();
+
+        let mut enc = BoxEncoder::with_buffer(KEY.clone(), Nonce(NONCE_BYTES), [0; 4096]);
+        let mut wire = vec![];
+        let mut input = &body[..];
+        while !enc.is_closed() {
+            let n = enc.write(input);
+            input = &input[n..];
+            if input.is_empty() {
+                enc.close();
+            }
+            // Send a few bytes at a time.
+            let out = enc.output();
+            let n = out.len().min(1000);
+            wire.extend_from_slice(&out[..n]);
+            enc.consume(n);
+        }
+        assert_eq!(wire, expected);
+
+        let mut dec = BoxDecoder::new(KEY.clone(), Nonce(NONCE_BYTES));
+        let mut got = vec![];
+        let mut input = &wire[..];
+        while !dec.is_closed() {
+            let n = dec.push(&input[..input.len().min(100)]).unwrap();
+            input = &input[n..];
+            let p = dec.plaintext();
+            let len = p.len();
+            got.extend_from_slice(p);
+            dec.consume(len);
+        }
+        assert_eq!(got, body);
+        assert!(input.is_empty());
+
+        let mut dec = BoxDecoder::new(KEY.clone(), Nonce(NONCE_BYTES));
+        wire[40] ^= 1;
+        let n = dec.push(&wire[..HEADER_SIZE]).unwrap();
+        assert_eq!(n, HEADER_SIZE);
+        assert_eq!(
+            dec.push(&wire[HEADER_SIZE..]),
+            Err(DecodeError::BodyOpenFailed)
+        );
+    }
+
+    #[cfg(all(feature = "embedded-io", feature = "embedded-io-async"))]
+    #[test]
+    fn embedded() {
+        use crate::embedded;
+
+        let body: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            boxw.write_all(&body).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        let expected = boxw.into_inner().into_inner();
+
+        let mut wire = vec![0; expected.len()];
+        let mut boxw =
+            embedded::BoxWriter::new(&mut wire[..], KEY.clone(), Nonce(NONCE_BYTES), [0; 4096]);
+        embedded_io::Write::write_all(&mut boxw, &body).unwrap();
+        boxw.close();
+        // Writing after close fails, without sending the goodbye.
+        let err = embedded_io::Write::write(&mut boxw, &[1]).unwrap_err();
+        assert!(matches!(err, embedded::Error::WriteZero));
+        assert!(!boxw.is_closed());
+        embedded_io::Write::flush(&mut boxw).unwrap();
+        assert!(boxw.is_closed());
+        let err = embedded_io::Write::write_all(&mut boxw, &[1, 2]).unwrap_err();
+        assert!(matches!(err, embedded::Error::WriteZero));
+        let err = block_on(embedded_io_async::Write::write(&mut boxw, &[1])).unwrap_err();
+        assert!(matches!(err, embedded::Error::WriteZero));
+        assert_eq!(embedded_io::Write::write(&mut boxw, &[]).unwrap(), 0);
+        assert_eq!(wire, expected);
+
+        let mut boxr =
+            embedded::BoxReader::new(&wire[..], KEY.clone(), Nonce(NONCE_BYTES), [0; 4096]);
+        let mut got = vec![0; body.len()];
+        embedded_io::Read::read_exact(&mut boxr, &mut got).unwrap();
+        assert_eq!(embedded_io::Read::read(&mut boxr, &mut [0; 10]).unwrap(), 0);
+        assert_eq!(got, body);
+
+        let mut boxr =
+            embedded::BoxReader::new(&wire[..], KEY.clone(), Nonce(NONCE_BYTES), [0; 4096]);
+        let mut got = vec![0; body.len()];
+        block_on(embedded_io_async::Read::read_exact(&mut boxr, &mut got)).unwrap();
+        assert_eq!(got, body);
+
+        // A truncated stream is an error, not an early end.
+        let mut boxr =
+            embedded::BoxReader::new(&wire[..100], KEY.clone(), Nonce(NONCE_BYTES), [0; 4096]);
+        let err = embedded_io::Read::read(&mut boxr, &mut got).unwrap_err();
+        assert!(matches!(err, embedded::Error::UnexpectedEof));
+    }
+
+    #[test]
+    fn inline_buffers() {
+        let mut boxw =
+            BoxWriter::<_, [u8; 100]>::inline(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        assert_eq!(boxw.box_size(), 100);
+        block_on(async {
+            boxw.write_all(&[7; 250]).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        assert_eq!(boxw.stats().boxes, 3);
+
+        let wire = boxw.into_inner().into_inner();
+        let mut boxr =
+            BoxReader::<_, [u8; 100]>::inline(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        let mut got = vec![];
+        block_on(boxr.read_to_end(&mut got)).unwrap();
+        assert_eq!(got, [7; 250]);
+
+        let boxw = BoxWriter::<_, [u8; 100]>::inline(vec![0u8; 0], KEY.clone(), Nonce(NONCE_BYTES));
+        assert!(boxw.is_strict());
+        let boxw = BoxWriter::<_, [u8; 8192]>::inline_extended(
+            vec![0u8; 0],
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+        );
+        assert!(!boxw.is_strict());
+        assert_eq!(boxw.box_size(), 8192);
+    }
+
+    #[test]
+    fn pooled_buffers() {
+        let pool = SharedPool::new(8);
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut boxw = BoxWriter::pooled(rbw, KEY.clone(), Nonce(NONCE_BYTES), pool.clone());
+        let mut boxr = BoxReader::pooled(rbr, KEY.clone(), Nonce(NONCE_BYTES), pool.clone());
+
+        block_on(async {
+            // Nothing's taken for an empty write, or by an idle writer.
+            pool.give(vec![0; 4096]);
+            boxw.write_all(&[]).await.unwrap();
+            boxw.flush().await.unwrap();
+            assert_eq!(pool.idle(), 1);
+            boxw.write_all(&[1; 10]).await.unwrap();
+            assert_eq!(pool.idle(), 0);
+            boxw.flush().await.unwrap();
+            assert_eq!(pool.idle(), 1);
+
+            // The reader borrows the writer's old buffer until it's read everything.
+            let mut buf = [0; 5];
+            boxr.read_exact(&mut buf).await.unwrap();
+            assert_eq!(pool.idle(), 0);
+            boxr.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [1; 5]);
+            assert_eq!(pool.idle(), 1);
+        });
+
+        // Buffers are zeroed before they go back to the pool.
+        assert_eq!(pool.take(4096), vec![0; 4096]);
+
+        // Buffers that aren't pooled work as before, and are never released.
+        let mut boxw = BoxWriter::with_buffer(vec![], KEY.clone(), Nonce(NONCE_BYTES), [0u8; 100]);
+        block_on(boxw.write_all(&[3; 10])).unwrap();
+        block_on(boxw.flush()).unwrap();
+        let mut slice = [0u8; 100];
+        let boxr =
+            BoxReader::with_buffer(&b""[..], KEY.clone(), Nonce(NONCE_BYTES), &mut slice[..]);
+        assert_eq!(boxr.max_body_size(), 100);
+
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut stream = BoxStream::pooled(
+            rbr,
+            rbw,
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+            pool.clone(),
+        );
+        block_on(async {
+            stream.write_all(&[2; 100]).await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0; 100];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [2; 100]);
+        });
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes() {
+        use ::bytes::{Buf, Bytes};
+
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+
+        let body: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let write = async {
+            boxw.write_bytes(Bytes::from(body.clone())).await.unwrap();
+            boxw.write_buf(Bytes::from_static(b"abc").chain(&b"def"[..]))
+                .await
+                .unwrap();
+            boxw.close().await.unwrap();
+        };
+        let read = async {
+            let mut boxes = vec![];
+            loop {
+                let b = boxr.read_box_bytes().await.unwrap();
+                if b.is_empty() {
+                    break boxes;
+                }
+                boxes.push(b);
+            }
+        };
+        let ((), boxes) = block_on(futures_util::future::join(write, read));
+        assert_eq!(boxes.len(), 3);
+        assert_eq!(&boxes[0][..], &body[..4096]);
+        assert_eq!(&boxes[1][..], &body[4096..]);
+        assert_eq!(&boxes[2][..], b"abcdef");
+
+        // A dropped read keeps the part of the body it read, whichever way
+        // the body is finished.
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            boxw.write_all(&body).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        let wire = boxw.into_inner().into_inner();
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+        for finish_with_read in [false, true] {
+            let (mut rbw, rbr) = async_ringbuffer::ring_buffer(wire.len());
+            let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+            block_on(rbw.write_all(&wire[..1000])).unwrap();
+            {
+                let mut fut = boxr.read_box_bytes();
+                assert!(core::future::Future::poll(Pin::new(&mut fut), &mut cx).is_pending());
+            }
+            block_on(rbw.write_all(&wire[1000..])).unwrap();
+            let mut got = vec![0; 4096];
+            if finish_with_read {
+                block_on(boxr.read_exact(&mut got)).unwrap();
+            } else {
+                got = block_on(boxr.read_box_bytes()).unwrap().to_vec();
+            }
+            assert_eq!(got, &body[..4096]);
+            assert_eq!(&block_on(boxr.read_box_bytes()).unwrap()[..], &body[4096..]);
+        }
+    }
+
+    // A timer whose sleeps all end when `fire` is set.
+    struct Manual(Arc<AtomicBool>);
+
+    impl Timer for Manual {
+        fn sleep(&self, _: Duration) -> Sleep {
+            let fire = self.0.clone();
+            Box::pin(futures_util::future::poll_fn(move |_| {
+                if fire.load(Ordering::SeqCst) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }))
+        }
+    }
+
+    #[test]
+    fn flush_policy() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+        let written = |w: &BoxWriter<Cursor<Vec<u8>>, Vec<u8>>| w.stats().ciphertext_bytes;
+        let new_writer = || BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+
+        let mut boxw = new_writer();
+        boxw.set_flush_policy(FlushPolicy::EveryWrite);
+        block_on(boxw.write_all(&[1, 2, 3])).unwrap();
+        assert_eq!(written(&boxw), 37);
+        assert_eq!(boxw.stats().flushes, 1);
+
+        let mut boxw = new_writer();
+        boxw.set_flush_policy(FlushPolicy::Threshold(10));
+        block_on(boxw.write_all(&[0; 5])).unwrap();
+        assert_eq!(written(&boxw), 0);
+        block_on(boxw.write_all(&[0; 5])).unwrap();
+        assert_eq!(written(&boxw), 44);
+
+        let fire = Arc::new(AtomicBool::new(false));
+        let mut boxw = new_writer();
+        boxw.set_timer(Manual(fire.clone()));
+        boxw.set_flush_policy(FlushPolicy::Delay(Duration::from_millis(10)));
+        block_on(boxw.write_all(&[1, 2, 3])).unwrap();
+        assert!(boxw.poll_auto_flush(&mut cx).is_pending());
+        assert_eq!(written(&boxw), 0);
+
+        fire.store(true, Ordering::SeqCst);
+        assert!(matches!(boxw.poll_auto_flush(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(written(&boxw), 37);
+        assert!(boxw.poll_auto_flush(&mut cx).is_pending());
+
+        // An automatic flush that fails is reported by the next flush.
+        let mut boxw = BoxWriter::new(FailFlush(true), KEY.clone(), Nonce(NONCE_BYTES));
+        boxw.set_flush_policy(FlushPolicy::EveryWrite);
+        block_on(boxw.write_all(&[1, 2, 3])).unwrap();
+        let err = block_on(boxw.flush()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        block_on(boxw.flush()).unwrap();
+
+        // Or by the next write, whichever way it's made.
+        for i in 0..2 {
+            let mut boxw = BoxWriter::new(FailFlush(true), KEY.clone(), Nonce(NONCE_BYTES));
+            boxw.set_flush_policy(FlushPolicy::EveryWrite);
+            block_on(boxw.write_all(&[1, 2, 3])).unwrap();
+            let err = match i {
+                0 => block_on(boxw.write_box(&[4])),
+                _ => block_on(boxw.write_owned(vec![4])),
+            }
+            .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::Other);
+            block_on(boxw.write_box(&[4])).unwrap();
+        }
+    }
+
+    // A writer whose first flush fails.
+    #[derive(Debug)]
+    struct FailFlush(bool);
+
+    impl AsyncWrite for FailFlush {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<std::io::Result<()>> {
+            if std::mem::take(&mut self.0) {
+                Poll::Ready(Err(std::io::ErrorKind::Other.into()))
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn cover_traffic() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+        let fire = Arc::new(AtomicBool::new(false));
+
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        boxw.set_timer(Manual(fire.clone()));
+        boxw.set_padding(Padding::Bucket(32));
+        boxw.set_cover_traffic(CoverTraffic::Idle(Duration::from_secs(1)));
+        block_on(boxw.write_all(&[1, 2, 3])).unwrap();
+        assert!(boxw.poll_cover_traffic(&mut cx).is_pending());
+
+        fire.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            boxw.poll_cover_traffic(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        // The cover box is padded like any other, and the plaintext stays buffered.
+        assert_eq!(boxw.stats().ciphertext_bytes, 34 + 32);
+        assert_eq!(boxw.buffered_len(), 3);
+
+        fire.store(false, Ordering::SeqCst);
+        assert!(boxw.poll_cover_traffic(&mut cx).is_pending());
+        boxw.set_cover_traffic(CoverTraffic::Off);
+        fire.store(true, Ordering::SeqCst);
+        assert!(boxw.poll_cover_traffic(&mut cx).is_pending());
+        block_on(boxw.close()).unwrap();
+        assert_eq!(boxw.stats().boxes, 2);
+
+        // Once closed, there's no more cover traffic.
+        boxw.set_cover_traffic(CoverTraffic::Idle(Duration::from_secs(1)));
+        fire.store(false, Ordering::SeqCst);
+        match boxw.poll_cover_traffic(&mut cx) {
+            Poll::Ready(Err(e)) => assert_eq!(e.kind(), std::io::ErrorKind::WriteZero),
+            p => panic!("{:?}", p),
+        }
+
+        let wire = boxw.into_inner().into_inner();
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_padded(true);
+        let mut got = vec![];
+        block_on(boxr.read_to_end(&mut got)).unwrap();
+        assert_eq!(got, [1, 2, 3]);
+        assert_eq!(boxr.stats().boxes, 2);
+    }
+
+    #[test]
+    fn read_box() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+
+        let mut wire = vec![];
+        let mut noncegen = NonceGen::with_starting_nonce(Nonce(NONCE_BYTES));
+        for body in [&[1, 2, 3][..], &[], &[4, 5, 6, 7], &[8, 9]] {
+            let mut body = body.to_vec();
+            let head = seal(&mut body, &KEY, &mut noncegen);
+            wire.extend_from_slice(head.as_bytes());
+            wire.extend_from_slice(&body);
+        }
+        let head = HeadPayload::goodbye().seal(&KEY, noncegen.next());
+        wire.extend_from_slice(head.as_bytes());
+
+        type Reader = BoxReader<Cursor<Vec<u8>>, Vec<u8>>;
+        let mut frame = |r: &mut Reader| match r.poll_read_box(&mut cx) {
+            Poll::Ready(Ok(f)) => (
+                f.body.to_vec(),
+                f.index,
+                f.nonce,
+                f.ciphertext_len,
+                f.goodbye,
+            ),
+            p => panic!("{:?}", p.map_ok(|_| ())),
+        };
+
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        assert_eq!(frame(&mut boxr), (vec![1, 2, 3], 0, 1, 37, false));
+        assert_eq!(frame(&mut boxr), (vec![], 1, 3, 34, false));
+
+        // Reads pick up where frames leave off, and the other way round.
+        let mut buf = [0; 1];
+        block_on(boxr.read_exact(&mut buf)).unwrap();
+        assert_eq!(buf, [4]);
+        assert_eq!(frame(&mut boxr), (vec![5, 6, 7], 2, 5, 38, false));
+        let mut rest = vec![];
+        block_on(boxr.read_to_end(&mut rest)).unwrap();
+        assert_eq!(rest, [8, 9]);
+
+        assert_eq!(frame(&mut boxr), (vec![], 4, 8, 34, true));
+        assert_eq!(boxr.stats().boxes, 4);
+    }
+
+    #[test]
+    fn write_box() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            boxw.write_all(&[1, 2, 3]).await.unwrap();
+            boxw.write_box(&[4; 10]).await.unwrap();
+            boxw.write_box(&[]).await.unwrap();
+
+            let err = boxw.write_box(&[0; MAX_BOX_SIZE + 1]).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            boxw.set_strict(false);
+            boxw.write_box(&[5; MAX_BOX_SIZE + 1]).await.unwrap();
+
+            boxw.write_all(&[6]).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        assert_eq!(boxw.stats().boxes, 5);
+
+        let wire = boxw.into_inner().into_inner();
+        let mut boxr = BoxReader::with_max_body_size(
+            Cursor::new(wire),
+            KEY.clone(),
+            Nonce(NONCE_BYTES),
+            MAX_EXTENDED_BOX_SIZE,
+        );
+        let mut bodies = vec![];
+        loop {
+            match boxr.poll_read_box(&mut cx) {
+                Poll::Ready(Ok(f)) if f.goodbye => break,
+                Poll::Ready(Ok(f)) => bodies.push(f.body.to_vec()),
+                p => panic!("{:?}", p.map_ok(|_| ())),
+            }
+        }
+        assert_eq!(
+            bodies,
+            [
+                vec![1, 2, 3],
+                vec![4; 10],
+                vec![],
+                vec![5; MAX_BOX_SIZE + 1],
+                vec![6]
+            ]
+        );
+    }
+
+    #[test]
+    fn padding() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+        let data: Vec<u8> = (0..100).collect();
+
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        boxw.set_padding(Padding::Bucket(64));
+        block_on(async {
+            boxw.write_all(&data).await.unwrap();
+            boxw.flush().await.unwrap();
+            boxw.write_box(&[1, 2, 3]).await.unwrap();
+            boxw.write_box(&[]).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        assert_eq!(boxw.stats().ciphertext_bytes, 4 * 34 + 128 + 64 + 64);
+        // Padding isn't counted as plaintext.
+        assert_eq!(boxw.stats().plaintext_bytes, 103);
+        assert_eq!(boxw.stats().body_sizes.counts()[0], 2);
+        assert_eq!(boxw.stats().body_sizes.counts()[3], 1);
+        let wire = boxw.into_inner().into_inner();
+
+        let mut boxr = BoxReader::new(Cursor::new(wire.clone()), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_padded(true);
+        let mut got = vec![];
+        block_on(boxr.read_to_end(&mut got)).unwrap();
+        assert_eq!(got, [&data[..], &[1, 2, 3]].concat());
+        assert_eq!(boxr.stats().plaintext_bytes, 103);
+        assert_eq!(boxr.stats().body_sizes.counts()[3], 1);
+
+        // The empty box is still a box.
+        let mut boxr = BoxReader::new(Cursor::new(wire.clone()), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_padded(true);
+        let mut sizes = vec![];
+        loop {
+            match boxr.poll_read_box(&mut cx) {
+                Poll::Ready(Ok(f)) if f.goodbye => break,
+                Poll::Ready(Ok(f)) => sizes.push((f.body.len(), f.ciphertext_len)),
+                p => panic!("{:?}", p.map_ok(|_| ())),
+            }
+        }
+        assert_eq!(sizes, [(100, 162), (3, 98), (0, 98)]);
+
+        // A reader that doesn't expect padding hands it out as data.
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        let mut got = vec![];
+        block_on(boxr.read_to_end(&mut got)).unwrap();
+        assert_eq!(got.len(), 128 + 64 + 64);
+
+        // Every box is the same size with `Fixed`, and unpadded boxes are refused.
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        boxw.set_box_size(100);
+        boxw.set_padding(Padding::Fixed);
+        block_on(async {
+            boxw.write_all(&[7; 250]).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        assert_eq!(boxw.stats().boxes, 3);
+        assert_eq!(boxw.stats().ciphertext_bytes, 3 * (34 + 100) + 34);
+
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(boxw.write_box(&[1, 2, 3])).unwrap();
+        let wire = boxw.into_inner().into_inner();
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_padded(true);
+        let err = block_on(boxr.read(&mut [0; 10])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn compression() {
+        let data: Vec<u8> = (0..1000)
+            .flat_map(|i| format!("{{\"seq\":{},\"type\":\"post\"}}", i).into_bytes())
+            .collect();
+
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        boxw.set_compression(Compression::Deflate(6));
+        boxw.set_padding(Padding::Bucket(64));
+        block_on(async {
+            boxw.write_all(&data).await.unwrap();
+            boxw.flush().await.unwrap();
+            boxw.write_box(&[]).await.unwrap();
+            boxw.write_box(&data[..10]).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        assert!(boxw.stats().ciphertext_bytes < data.len() as u64 / 4);
+        // Stats count the bytes before compression.
+        let plaintext_bytes = data.len() as u64 + 10;
+        assert_eq!(boxw.stats().plaintext_bytes, plaintext_bytes);
+        assert_eq!(boxw.stats().body_sizes.counts()[8], 6);
+        let wire = boxw.into_inner().into_inner();
+
+        let mut boxr = BoxReader::new(Cursor::new(wire.clone()), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_compressed(true);
+        boxr.set_padded(true);
+        let mut got = vec![];
+        block_on(boxr.read_to_end(&mut got)).unwrap();
+        assert_eq!(got, [&data[..], &data[..10]].concat());
+        assert_eq!(boxr.stats().plaintext_bytes, plaintext_bytes);
+
+        #[cfg(feature = "bytes")]
+        {
+            let mut boxr =
+                BoxReader::new(Cursor::new(wire.clone()), KEY.clone(), Nonce(NONCE_BYTES));
+            boxr.set_compressed(true);
+            boxr.set_padded(true);
+            let mut got = vec![];
+            block_on(async {
+                loop {
+                    let b = boxr.read_box_bytes().await.unwrap();
+                    if b.is_empty() {
+                        break;
+                    }
+                    got.extend_from_slice(&b);
+                }
+            });
+            assert_eq!(got, [&data[..], &data[..10]].concat());
+        }
+
+        // A reader that doesn't expect compression can't make sense of it.
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_padded(true);
+        let mut got = vec![];
+        block_on(boxr.read_to_end(&mut got)).unwrap();
+        assert_ne!(got, [&data[..], &data[..10]].concat());
+
+        // Bodies that inflate to more than the reader allows are refused.
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        boxw.set_compression(Compression::Deflate(9));
+        block_on(boxw.write_all(&[0; MAX_BOX_SIZE])).unwrap();
+        block_on(boxw.close()).unwrap();
+        let wire = boxw.into_inner().into_inner();
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_compressed(true);
+        boxr.set_max_inflated_size(1000);
+        let err = block_on(boxr.read_to_end(&mut vec![])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Bulk writes compress the same way, and bulk reads inflate them.
+        #[cfg(feature = "rayon")]
+        {
+            let mut owned = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+            let mut bulk = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+            owned.set_compression(Compression::Deflate(6));
+            bulk.set_compression(Compression::Deflate(6));
+            block_on(async {
+                owned.write_owned(data.clone()).await.unwrap();
+                owned.close().await.unwrap();
+                bulk.write_bulk(data.clone()).await.unwrap();
+                bulk.close().await.unwrap();
+            });
+            assert_eq!(bulk.stats().plaintext_bytes, data.len() as u64);
+            assert_eq!(owned.stats(), bulk.stats());
+            let wire = bulk.into_inner().into_inner();
+            assert_eq!(owned.into_inner().into_inner(), wire);
+
+            let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+            boxr.set_compressed(true);
+            let got = block_on(boxr.read_bulk(usize::MAX)).unwrap();
+            assert_eq!(got, data);
+            assert_eq!(boxr.stats().plaintext_bytes, data.len() as u64);
+        }
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn incompressible() {
+        // Data that deflate can't shrink still fits in the biggest boxes.
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let data: Vec<u8> = (0..200_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+
+        for padding in [Padding::Off, Padding::Fixed] {
+            let mut boxw = BoxWriter::with_box_size(
+                Cursor::new(vec![]),
+                KEY.clone(),
+                Nonce(NONCE_BYTES),
+                MAX_EXTENDED_BOX_SIZE,
+            );
+            boxw.set_padding(padding);
+            boxw.set_compression(Compression::Deflate(9));
+            block_on(async {
+                boxw.write_all(&data).await.unwrap();
+                boxw.close().await.unwrap();
+            });
+            let wire = boxw.into_inner().into_inner();
+
+            let mut boxr = BoxReader::with_max_body_size(
+                Cursor::new(wire),
+                KEY.clone(),
+                Nonce(NONCE_BYTES),
+                MAX_EXTENDED_BOX_SIZE,
+            );
+            boxr.set_compressed(true);
+            boxr.set_padded(padding != Padding::Off);
+            boxr.set_max_inflated_size(MAX_EXTENDED_BOX_SIZE);
+            let mut got = vec![];
+            block_on(boxr.read_to_end(&mut got)).unwrap();
+            assert_eq!(got, data);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn padded_bulk() {
+        let body: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+
+        let mut owned = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        let mut bulk = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        owned.set_padding(Padding::Bucket(1000));
+        bulk.set_padding(Padding::Bucket(1000));
+        block_on(async {
+            owned.write_owned(body.clone()).await.unwrap();
+            owned.close().await.unwrap();
+            bulk.write_bulk(body.clone()).await.unwrap();
+            bulk.close().await.unwrap();
+        });
+        assert_eq!(bulk.stats().plaintext_bytes, 20_000);
+        assert_eq!(owned.stats(), bulk.stats());
+        let wire = bulk.into_inner().into_inner();
+        assert_eq!(owned.into_inner().into_inner(), wire);
+
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        boxr.set_padded(true);
+        let mut got = vec![];
+        block_on(async {
+            loop {
+                let chunk = boxr.read_bulk(100_000).await.unwrap();
+                if chunk.is_empty() {
+                    break;
+                }
+                got.extend_from_slice(&chunk);
+            }
+        });
+        assert_eq!(got, body);
+        assert_eq!(boxr.stats().plaintext_bytes, 20_000);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn bulk() {
+        let body: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+
+        let mut owned = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        let mut bulk = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            owned.write_all(&[1, 2, 3]).await.unwrap();
+            owned.write_owned(body.clone()).await.unwrap();
+            owned.close().await.unwrap();
+
+            bulk.write_all(&[1, 2, 3]).await.unwrap();
+            bulk.write_bulk(body.clone()).await.unwrap();
+            bulk.close().await.unwrap();
+        });
+        assert_eq!(owned.stats(), bulk.stats());
+        let wire = bulk.into_inner().into_inner();
+        assert_eq!(owned.into_inner().into_inner(), wire);
+
+        let mut boxr = BoxReader::new(Cursor::new(wire), KEY.clone(), Nonce(NONCE_BYTES));
+        let mut got = vec![0; 2];
+        block_on(async {
+            boxr.read_exact(&mut got).await.unwrap();
+            loop {
+                let chunk = boxr.read_bulk(10_000).await.unwrap();
+                if chunk.is_empty() {
+                    break;
+                }
+                assert!(chunk.len() < 10_000 + MAX_BOX_SIZE);
+                got.extend_from_slice(&chunk);
+            }
+        });
+        assert_eq!(got, [&[1, 2, 3][..], &body].concat());
+        assert_eq!(boxr.stats().boxes, 6);
+        assert!(boxr.is_closed());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn coalesced_bulk() {
+        use futures_io::IoSlice;
+
+        // Counts vectored writes, and takes at most `max` bytes from each.
+        struct Writes {
+            out: Vec<u8>,
+            writes: usize,
+            max: usize,
+        }
+
+        impl AsyncWrite for Writes {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<Result<usize, std::io::Error>> {
+                self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+            }
+
+            fn poll_write_vectored(
+                mut self: Pin<&mut Self>,
+                _: &mut Context,
+                bufs: &[IoSlice],
+            ) -> Poll<Result<usize, std::io::Error>> {
+                self.writes += 1;
+                let mut n = 0;
+                for b in bufs {
+                    let take = b.len().min(self.max - n);
+                    self.out.extend_from_slice(&b[..take]);
+                    n += take;
+                }
+                Poll::Ready(Ok(n))
+            }
+
+            fn poll_flush(
+                self: Pin<&mut Self>,
+                _: &mut Context,
+            ) -> Poll<Result<(), std::io::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(
+                self: Pin<&mut Self>,
+                _: &mut Context,
+            ) -> Poll<Result<(), std::io::Error>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let body: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut expected = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(expected.write_owned(body.clone())).unwrap();
+        let expected = expected.into_inner().into_inner();
+
+        // The 25 boxes go out in one write, or are split wherever the inner
+        // writer stops taking bytes.
+        for max in [1 << 20, 5000, 30] {
+            let inner = Writes {
+                out: vec![],
+                writes: 0,
+                max,
+            };
+            let mut boxw = BoxWriter::new(inner, KEY.clone(), Nonce(NONCE_BYTES));
+            block_on(boxw.write_bulk(body.clone())).unwrap();
+            assert_eq!(boxw.stats().boxes, 25);
+            let inner = boxw.into_inner();
+            assert_eq!(inner.out, expected);
+            assert_eq!(inner.writes, expected.len().div_ceil(max));
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn dropped_bulk() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+        let body: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        // Boxes sealed by a dropped write_bulk are still sent by the next flush.
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(1024);
+        let mut boxw = BoxWriter::new(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+        {
+            let mut fut = boxw.write_bulk(body.clone());
+            assert!(core::future::Future::poll(Pin::new(&mut fut), &mut cx).is_pending());
+        }
+        assert!(boxw.pending_ciphertext_len() > 1024);
+        let write = async {
+            boxw.close().await.unwrap();
+        };
+        let read = async {
+            let mut buf = vec![];
+            boxr.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let ((), got) = block_on(futures_util::future::join(write, read));
+        assert_eq!(got, body);
+
+        let mut boxw = BoxWriter::new(Cursor::new(vec![]), KEY.clone(), Nonce(NONCE_BYTES));
+        block_on(async {
+            boxw.write_bulk(body.clone()).await.unwrap();
+            boxw.close().await.unwrap();
+        });
+        let wire = boxw.into_inner().into_inner();
+
+        // A dropped read_bulk leaves a partial body in the reader.
+        let (mut rbw, rbr) = async_ringbuffer::ring_buffer(wire.len());
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+        let n = Head::SIZE + MAX_BOX_SIZE + 1000;
+        block_on(rbw.write_all(&wire[..n])).unwrap();
+        let mut got = vec![];
+        {
+            let mut fut = boxr.read_bulk(usize::MAX);
+            match core::future::Future::poll(Pin::new(&mut fut), &mut cx) {
+                Poll::Ready(Ok(chunk)) => got.extend_from_slice(&chunk),
+                p => panic!("{:?}", p),
+            }
+            assert!(core::future::Future::poll(Pin::new(&mut fut), &mut cx).is_pending());
+        }
+        assert_eq!(got.len(), MAX_BOX_SIZE);
+        block_on(async {
+            rbw.write_all(&wire[n..]).await.unwrap();
+            loop {
+                let chunk = boxr.read_bulk(usize::MAX).await.unwrap();
+                if chunk.is_empty() {
+                    break;
+                }
+                got.extend_from_slice(&chunk);
+            }
+        });
+        assert_eq!(got, body);
+    }
+
+    #[test]
+    fn double_buffered() {
+        let wk = noop_waker();
+        let mut cx = Context::from_waker(&wk);
+
+        let (rbw, rbr) = async_ringbuffer::ring_buffer(100);
+        let mut boxw = BoxWriter::double_buffered(rbw, KEY.clone(), Nonce(NONCE_BYTES));
+        let mut boxr = BoxReader::new(rbr, KEY.clone(), Nonce(NONCE_BYTES));
+
+        let body: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        // The first box fills the front buffer and gets stuck on the way out,
+        // but the next 4096 bytes still fit in the other buffer.
+        let mut w = Pin::new(&mut boxw);
+        match w.as_mut().poll_write(&mut cx, &body) {
+            Poll::Ready(Ok(8192)) => {}
+            p => panic!("{:?}", p),
+        }
+        assert_eq!(w.buffered_len(), 4096);
+        assert!(w.pending_ciphertext_len() > 0);
+        assert!(w.as_mut().poll_write(&mut cx, &body[8192..]).is_pending());
+
+        let write = async {
+            boxw.write_all(&body[8192..]).await.unwrap();
+            boxw.close().await.unwrap();
+        };
+        let read = async {
+            let mut buf = vec![];
+            boxr.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let ((), got) = block_on(futures_util::future::join(write, read));
+        assert_eq!(got, body);
+        assert_eq!(boxw.stats().boxes, 3);
+    }
 }
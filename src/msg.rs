@@ -1,6 +1,7 @@
 use crate::bytes::*;
 
 use byteorder::BigEndian;
+use core::fmt;
 use core::mem::size_of;
 use ssb_crypto::secretbox::{Hmac, Key, Nonce};
 use zerocopy::byteorder::U16;
@@ -13,6 +14,13 @@ pub struct Head {
     hbox: [u8; 18],
 }
 
+impl fmt::Debug for Head {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Sealed; nothing in here is meaningful without the key.
+        f.debug_struct("Head").finish_non_exhaustive()
+    }
+}
+
 impl Head {
     pub const SIZE: usize = size_of::<Self>();
 
@@ -32,6 +40,15 @@ pub struct HeadPayload {
     pub body_hmac: Hmac,
 }
 
+impl fmt::Debug for HeadPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HeadPayload")
+            .field("body_size", &self.body_size.get())
+            .field("goodbye", &self.is_goodbye())
+            .finish_non_exhaustive()
+    }
+}
+
 impl HeadPayload {
     pub fn new(body_size: u16, body_hmac: Hmac) -> HeadPayload {
         HeadPayload {
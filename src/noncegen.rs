@@ -5,19 +5,29 @@ use ssb_crypto::secretbox::Nonce;
 /// This isn't an iterator, but it probably should be.
 pub struct NonceGen {
     next_nonce: Nonce,
+    position: u64,
 }
 
 impl NonceGen {
     /// Create a series of nonces, with the specified starting nonce.
     ///
     pub fn with_starting_nonce(nonce: Nonce) -> NonceGen {
-        NonceGen { next_nonce: nonce }
+        NonceGen {
+            next_nonce: nonce,
+            position: 0,
+        }
+    }
+
+    /// The number of nonces generated so far.
+    pub fn position(&self) -> u64 {
+        self.position
     }
 
     /// Generate the next nonce in the series.
     /// This treats the underlying bytes as a big-endian number, and increments.
     pub fn next(&mut self) -> Nonce {
         let n = self.next_nonce;
+        self.position += 1;
 
         // Increment the nonce as a big-endian u24
         for byte in self.next_nonce.0.iter_mut().rev() {
@@ -47,3 +57,15 @@ fn increment() {
         &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]
     );
 }
+
+#[test]
+fn position() {
+    use crate::NonceGen;
+    use ssb_crypto::secretbox::Nonce;
+
+    let mut gen = NonceGen::with_starting_nonce(Nonce([0; 24]));
+    assert_eq!(gen.position(), 0);
+    gen.next();
+    gen.next();
+    assert_eq!(gen.position(), 2);
+}
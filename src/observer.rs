@@ -0,0 +1,57 @@
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, rc::Rc, sync::Arc};
+
+/// Callbacks for box-level events on a [`BoxReader`](./struct.BoxReader.html)
+/// or [`BoxWriter`](./struct.BoxWriter.html).
+///
+/// All methods have empty default implementations. The default observer is `()`,
+/// which ignores everything and costs nothing.
+///
+/// `index` is the position of the box in its direction of the stream, starting at 0.
+/// `len` is the size of the box's plaintext, without padding and before compression.
+/// Observers of a [`BoxStream`](./struct.BoxStream.html) are cloned into both halves;
+/// wrap the observer in an `Arc` (or `Rc`) if the halves should share state.
+pub trait BoxObserver {
+    /// An authenticated box was received and decrypted. Empty boxes are included.
+    fn on_box_opened(&self, _index: u64, _len: usize) {}
+
+    /// A box was sealed, and is about to be sent.
+    fn on_box_sealed(&self, _index: u64, _len: usize) {}
+
+    /// The peer said goodbye; the stream is finished.
+    fn on_goodbye_received(&self) {}
+
+    /// The goodbye header was sent to the peer.
+    fn on_goodbye_sent(&self) {}
+
+    /// The header or body of box `index` failed authentication.
+    fn on_auth_failure(&self, _index: u64) {}
+}
+
+impl BoxObserver for () {}
+
+macro_rules! forward_observer {
+    ($($ptr:ty),*) => {$(
+        impl<O: BoxObserver + ?Sized> BoxObserver for $ptr {
+            fn on_box_opened(&self, index: u64, len: usize) {
+                (**self).on_box_opened(index, len)
+            }
+            fn on_box_sealed(&self, index: u64, len: usize) {
+                (**self).on_box_sealed(index, len)
+            }
+            fn on_goodbye_received(&self) {
+                (**self).on_goodbye_received()
+            }
+            fn on_goodbye_sent(&self) {
+                (**self).on_goodbye_sent()
+            }
+            fn on_auth_failure(&self, index: u64) {
+                (**self).on_auth_failure(index)
+            }
+        }
+    )*};
+}
+
+forward_observer!(&O);
+#[cfg(feature = "alloc")]
+forward_observer!(Box<O>, Rc<O>, Arc<O>);
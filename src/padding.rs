@@ -0,0 +1,96 @@
+//! Padding of box bodies, so that box sizes don't give away the lengths of
+//! the messages inside them.
+//!
+//! A padded body is the plaintext, then a `0x80` byte, then zeros up to the
+//! padded size (as in ISO/IEC 7816-4). This isn't part of the box-stream
+//! protocol: both peers have to turn it on, and a peer that doesn't will see
+//! the padding as part of the data.
+
+/// How a [`BoxWriter`](./struct.BoxWriter.html) pads the bodies of its boxes.
+/// The peer's reader needs [`set_padded`](./struct.BoxReader.html#method.set_padded)
+/// to strip it off again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// Bodies hold exactly the bytes written, as in plain box-stream.
+    #[default]
+    Off,
+    /// Pad each body up to a multiple of this many bytes.
+    Bucket(usize),
+    /// Pad each body up to the writer's box size, so every box that's sent
+    /// is the same size. Boxes from `write_box` that are bigger than that are
+    /// padded up to a multiple of it.
+    Fixed,
+}
+
+const MARKER: u8 = 0x80;
+
+impl Padding {
+    /// The number of bytes of a body that padding takes up, at the least.
+    pub(crate) fn overhead(self) -> usize {
+        match self {
+            Padding::Off => 0,
+            _ => 1,
+        }
+    }
+
+    /// The size of the body that holds `len` bytes of plaintext.
+    /// The result is at most `limit`, as long as `len + 1` is.
+    pub(crate) fn padded_len(self, len: usize, box_size: usize, limit: usize) -> usize {
+        let bucket = match self {
+            Padding::Off => return len,
+            Padding::Bucket(n) => n,
+            Padding::Fixed => box_size,
+        };
+        let padded = (len + 1).div_ceil(bucket) * bucket;
+        padded.min(limit).max(len + 1)
+    }
+}
+
+/// Append padding to the body at the end of `buf` that starts at `start`,
+/// bringing it up to `padded_len` bytes.
+pub(crate) fn pad(buf: &mut Vec<u8>, start: usize, padded_len: usize) {
+    buf.push(MARKER);
+    buf.resize(start + padded_len, 0);
+}
+
+/// Pad the body of `len` bytes at the start of `buf`, filling the rest of `buf`.
+pub(crate) fn pad_in_place(buf: &mut [u8], len: usize) {
+    buf[len] = MARKER;
+    buf[len + 1..].fill(0);
+}
+
+/// The length of the plaintext in a padded body, or `None` if the padding is missing.
+pub(crate) fn unpadded_len(body: &[u8]) -> Option<usize> {
+    let end = body.iter().rposition(|b| *b != 0)?;
+    if body[end] == MARKER {
+        Some(end)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn pad_and_unpad() {
+    for (policy, len, padded) in [
+        (Padding::Bucket(16), 0, 16),
+        (Padding::Bucket(16), 15, 16),
+        (Padding::Bucket(16), 16, 32),
+        (Padding::Bucket(16), 4095, 4096),
+        (Padding::Fixed, 10, 100),
+        (Padding::Fixed, 150, 200),
+        (Padding::Fixed, 4095, 4096),
+    ] {
+        assert_eq!(policy.padded_len(len, 100, 4096), padded);
+
+        let mut body = vec![7; len];
+        pad(&mut body, 0, padded);
+        assert_eq!(body.len(), padded);
+        assert_eq!(unpadded_len(&body), Some(len));
+
+        let mut in_place = vec![7; padded];
+        pad_in_place(&mut in_place, len);
+        assert_eq!(in_place, body);
+    }
+    assert_eq!(unpadded_len(&[1, 2, 0]), None);
+    assert_eq!(unpadded_len(&[]), None);
+}
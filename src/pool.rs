@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+
+/// A source of buffers for [`Pooled`](./struct.Pooled.html).
+pub trait BufferProvider {
+    /// Get a buffer of `len` bytes. Its contents don't matter.
+    fn take(&self, len: usize) -> Vec<u8>;
+
+    /// Hand back a buffer that came from `take`.
+    fn give(&self, buf: Vec<u8>);
+}
+
+/// A pool of buffers that can be shared by any number of streams. Cloning it
+/// gives another handle to the same pool.
+///
+/// At most `max_idle` buffers are kept around; any more are freed when they're given back.
+#[derive(Clone, Debug)]
+pub struct SharedPool {
+    idle: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_idle: usize,
+}
+
+impl SharedPool {
+    pub fn new(max_idle: usize) -> SharedPool {
+        SharedPool {
+            idle: Arc::new(Mutex::new(Vec::new())),
+            max_idle,
+        }
+    }
+
+    /// The number of buffers waiting in the pool.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+impl BufferProvider for SharedPool {
+    fn take(&self, len: usize) -> Vec<u8> {
+        let mut buf = self.idle.lock().unwrap().pop().unwrap_or_default();
+        buf.resize(len, 0);
+        buf
+    }
+
+    fn give(&self, buf: Vec<u8>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+/// A buffer that's only held while it's in use, and otherwise lives in a
+/// [`BufferProvider`](./trait.BufferProvider.html).
+///
+/// Readers and writers made with `pooled` constructors, like
+/// [`BoxWriter::pooled`](./struct.BoxWriter.html#method.pooled), release it
+/// between boxes. Handed to `with_buffer`, it's held for as long as the
+/// reader or writer is.
+#[derive(Debug)]
+pub struct Pooled<P: BufferProvider> {
+    provider: P,
+    len: usize,
+    buf: Option<Vec<u8>>,
+}
+
+impl<P: BufferProvider> Pooled<P> {
+    /// A buffer of `len` bytes, which isn't taken from the provider until it's needed.
+    pub fn new(provider: P, len: usize) -> Pooled<P> {
+        Pooled {
+            provider,
+            len,
+            buf: None,
+        }
+    }
+
+    /// Whether a buffer is currently taken from the provider.
+    pub fn is_held(&self) -> bool {
+        self.buf.is_some()
+    }
+
+    /// Zero the buffer and give it back to the provider, if it's taken.
+    /// It's taken again the next time it's used.
+    pub fn release(&mut self) {
+        if let Some(mut buf) = self.buf.take() {
+            buf.fill(0);
+            self.provider.give(buf);
+        }
+    }
+}
+
+impl<P: BufferProvider> AsMut<[u8]> for Pooled<P> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        let (provider, len) = (&self.provider, self.len);
+        self.buf.get_or_insert_with(|| provider.take(len))
+    }
+}
+
+impl<P: BufferProvider> Drop for Pooled<P> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
@@ -1,15 +1,37 @@
-use crate::bytes::cast_mut;
+use crate::codec::{BoxOpener, BoxSize, DecodeError, Header, MAX_BOX_SIZE, MAX_EXTENDED_BOX_SIZE};
+#[cfg(feature = "flate2")]
+use crate::compress::{InflateError, Inflater};
 use crate::msg::*;
+use crate::observer::BoxObserver;
+use crate::padding::unpadded_len;
+use crate::pool::{BufferProvider, Pooled};
+use crate::readahead::ReadAhead;
+use crate::stats::Stats;
+use crate::trace::inner_error;
 
-use crate::NonceGen;
 use core::cmp::min;
+use core::fmt;
+#[cfg(any(feature = "bytes", feature = "rayon"))]
+use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
 use futures_core::ready;
-use futures_io::{self as io, AsyncRead};
+use futures_io::{self as io, AsyncBufRead, AsyncRead, IoSliceMut};
 use ssb_crypto::secretbox::{Key, Nonce};
 use thiserror::Error;
 
+mod frame;
+pub use frame::BoxFrame;
+
+#[cfg(feature = "bytes")]
+mod buf;
+#[cfg(feature = "bytes")]
+pub use buf::*;
+#[cfg(feature = "rayon")]
+mod bulk;
+#[cfg(feature = "rayon")]
+pub use bulk::*;
+
 #[derive(Debug, Error)]
 enum BoxStreamError {
     #[error("IO error: {source}")]
@@ -17,10 +39,16 @@ enum BoxStreamError {
         #[from]
         source: io::Error,
     },
-    #[error("Failed to decrypt header")]
-    HeaderOpenFailed,
-    #[error("Failed to decrypt body")]
-    BodyOpenFailed,
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error("Box body isn't padded")]
+    BadPadding,
+    #[cfg(feature = "flate2")]
+    #[error("Failed to inflate box body")]
+    InflateFailed,
+    #[cfg(feature = "flate2")]
+    #[error("Box body inflates to more than {max} bytes")]
+    InflatedTooLarge { max: usize },
 }
 
 impl From<BoxStreamError> for io::Error {
@@ -32,43 +60,273 @@ impl From<BoxStreamError> for io::Error {
     }
 }
 
-pub struct BoxReader<R, B> {
+/// Reads boxes from `inner`, and opens them.
+///
+/// Empty boxes carry no data, so they're skipped instead of being read as the
+/// end of the stream; only the goodbye ends it. If `inner` ends before the
+/// goodbye, reads fail with `UnexpectedEof`.
+pub struct BoxReader<R, B, O = ()> {
     inner: R,
     buffer: B,
+    buffer_size: usize,
+    // Called on the buffer when it isn't holding anything; see `pooled`.
+    release: fn(&mut B),
     state: State,
-    key: Key,
-    nonces: NonceGen,
+    max_body_size: usize,
+    // The size of the current box's body on the wire, padding included.
+    sealed_size: usize,
+    padded: bool,
+    // Decompresses bodies, and holds the plaintext of the current one.
+    #[cfg(feature = "flate2")]
+    inflater: Option<Inflater>,
+    #[cfg(feature = "flate2")]
+    max_inflated_size: usize,
+    opener: BoxOpener,
+    read_ahead: ReadAhead,
+    // Where `read_box_bytes` reads bodies. Holds the part of a body that's been
+    // read so far, if it was left unfinished.
+    #[cfg(feature = "bytes")]
+    bytes: ::bytes::BytesMut,
+    stats: Stats,
+    failed: bool,
+    observer: O,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
-impl<R, B> BoxReader<R, B> {
-    pub fn with_buffer(inner: R, key: Key, nonce: Nonce, buffer: B) -> BoxReader<R, B> {
+impl<R, B: AsMut<[u8]>> BoxReader<R, B> {
+    /// Create a reader that opens boxes in `buffer`. It accepts bodies as big
+    /// as the buffer.
+    pub fn with_buffer(inner: R, key: Key, nonce: Nonce, mut buffer: B) -> BoxReader<R, B> {
+        let size = buffer.as_mut().len();
+        BoxReader::with_sized_buffer(inner, key, nonce, buffer, size)
+    }
+
+    fn with_sized_buffer(
+        inner: R,
+        key: Key,
+        nonce: Nonce,
+        buffer: B,
+        buffer_size: usize,
+    ) -> BoxReader<R, B> {
         BoxReader {
             inner,
             buffer,
+            buffer_size,
+            release: |_| {},
             state: State::ReadingHead {
                 head: [0; Head::SIZE],
                 pos: 0,
             },
-            key,
-            nonces: NonceGen::with_starting_nonce(nonce),
+            max_body_size: buffer_size,
+            sealed_size: 0,
+            padded: false,
+            #[cfg(feature = "flate2")]
+            inflater: None,
+            #[cfg(feature = "flate2")]
+            max_inflated_size: MAX_EXTENDED_BOX_SIZE,
+            opener: BoxOpener::new(key, nonce),
+            read_ahead: ReadAhead::default(),
+            #[cfg(feature = "bytes")]
+            bytes: ::bytes::BytesMut::new(),
+            stats: Stats::default(),
+            failed: false,
+            observer: (),
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        }
+    }
+}
+
+impl<R, B, O> BoxReader<R, B, O> {
+    /// Replace the reader's observer, which is notified of box-level events.
+    pub fn with_observer<P: BoxObserver>(self, observer: P) -> BoxReader<R, B, P> {
+        BoxReader {
+            inner: self.inner,
+            buffer: self.buffer,
+            buffer_size: self.buffer_size,
+            release: self.release,
+            state: self.state,
+            max_body_size: self.max_body_size,
+            sealed_size: self.sealed_size,
+            padded: self.padded,
+            #[cfg(feature = "flate2")]
+            inflater: self.inflater,
+            #[cfg(feature = "flate2")]
+            max_inflated_size: self.max_inflated_size,
+            opener: self.opener,
+            read_ahead: self.read_ahead,
+            #[cfg(feature = "bytes")]
+            bytes: self.bytes,
+            stats: self.stats,
+            failed: self.failed,
+            observer,
+            #[cfg(feature = "tracing")]
+            span: self.span,
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Record this reader's events inside the given span.
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: tracing::Span) {
+        self.span = span;
+    }
+
+    /// Read up to `size` bytes of ciphertext from the inner reader at a time,
+    /// and decode as many boxes as possible from them before reading again.
+    /// This cuts down on the number of reads when boxes are small or arrive in bursts.
+    /// A size of zero (the default) turns read-ahead off; each read then asks for
+    /// exactly the rest of the current header or body.
+    ///
+    /// Note that any bytes that have been read ahead are lost by [`into_inner`](#method.into_inner).
+    pub fn set_read_ahead(&mut self, size: usize) {
+        self.read_ahead.set_size(size);
+    }
+
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead.size()
+    }
+
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// Strip the padding that a peer's writer adds to box bodies when it has
+    /// [`set_padding`](./struct.BoxWriter.html#method.set_padding). A body
+    /// without padding is then an error, so this can't be used with a peer
+    /// that doesn't pad.
+    pub fn set_padded(&mut self, padded: bool) {
+        self.padded = padded;
+    }
+
+    pub fn is_padded(&self) -> bool {
+        self.padded
+    }
+
+    /// Decompress box bodies from a peer's writer that has
+    /// [`set_compression`](./struct.BoxWriter.html#method.set_compression).
+    /// A body that doesn't decompress is then an error. Turn this on before
+    /// the first box is read, since the peer compresses each box with the
+    /// help of the ones before it.
+    #[cfg(feature = "flate2")]
+    pub fn set_compressed(&mut self, compressed: bool) {
+        if !compressed {
+            self.inflater = None;
+        } else if self.inflater.is_none() {
+            self.inflater = Some(Inflater::new());
         }
     }
 
+    #[cfg(feature = "flate2")]
+    pub fn is_compressed(&self) -> bool {
+        self.inflater.is_some()
+    }
+
+    /// Set the most plaintext that one compressed body may decompress to.
+    /// A body that comes to more is an error, which protects the reader from
+    /// decompression bombs. The default, 65535 bytes, is the most that a
+    /// writer puts in one box.
+    #[cfg(feature = "flate2")]
+    pub fn set_max_inflated_size(&mut self, size: usize) {
+        self.max_inflated_size = size;
+    }
+
+    #[cfg(feature = "flate2")]
+    pub fn max_inflated_size(&self) -> usize {
+        self.max_inflated_size
+    }
+
     pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// The number of decrypted bytes that can be read without touching the inner reader.
+    pub fn available(&self) -> usize {
         match self.state {
-            State::Done => true,
-            _ => false,
+            State::Ready { body_size, pos } => body_size - pos,
+            _ => 0,
         }
     }
 
+    /// A snapshot of the traffic read so far.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
     pub fn into_inner(self) -> R {
         self.inner
     }
 }
 
+impl<R, B: AsMut<[u8]>, O> BoxReader<R, B, O> {
+    /// Set the biggest box body that will be accepted. A bigger one is an error.
+    ///
+    /// # Panics
+    /// Panics if `size` is bigger than the buffer.
+    pub fn set_max_body_size(&mut self, size: usize) {
+        assert!(
+            size <= self.buffer_size,
+            "max body size {} is bigger than the buffer",
+            size
+        );
+        self.max_body_size = size;
+    }
+}
+
+impl<R, const N: usize> BoxReader<R, [u8; N]> {
+    /// Create a reader with an inline buffer, which accepts bodies of up to `N` bytes.
+    /// Use an `N` of 4096 to accept every box allowed by the protocol.
+    /// An `N` of zero or more than 65535 is a compile-time error.
+    pub fn inline(inner: R, key: Key, nonce: Nonce) -> BoxReader<R, [u8; N]> {
+        let () = BoxSize::<N, MAX_EXTENDED_BOX_SIZE>::CHECK;
+        BoxReader::with_buffer(inner, key, nonce, [0; N])
+    }
+}
+
+impl<R, P: BufferProvider> BoxReader<R, Pooled<P>> {
+    /// Like [`new`](#method.new), but the buffer comes from `provider`, and is
+    /// only held while a box's body is being read. It's zeroed before it goes back.
+    pub fn pooled(inner: R, key: Key, nonce: Nonce, provider: P) -> BoxReader<R, Pooled<P>> {
+        let buffer = Pooled::new(provider, MAX_BOX_SIZE);
+        let mut r = BoxReader::with_sized_buffer(inner, key, nonce, buffer, MAX_BOX_SIZE);
+        r.release = Pooled::release;
+        r
+    }
+}
+
 impl<R> BoxReader<R, Vec<u8>> {
     pub fn new(inner: R, key: Key, nonce: Nonce) -> BoxReader<R, Vec<u8>> {
-        BoxReader::with_buffer(inner, key, nonce, std::vec![0; 4096])
+        BoxReader::with_buffer(inner, key, nonce, std::vec![0; MAX_BOX_SIZE])
+    }
+
+    /// Create a reader that accepts bodies of up to `size` bytes, for talking to
+    /// writers that have strict mode turned off.
+    pub fn with_max_body_size(
+        inner: R,
+        key: Key,
+        nonce: Nonce,
+        size: usize,
+    ) -> BoxReader<R, Vec<u8>> {
+        BoxReader::with_buffer(inner, key, nonce, std::vec![0; size])
+    }
+}
+
+impl<R: fmt::Debug, B, O> fmt::Debug for BoxReader<R, B, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The key, nonces and buffer contents are deliberately left out.
+        f.debug_struct("BoxReader")
+            .field("inner", &self.inner)
+            .field("state", &self.state)
+            .field("max_body_size", &self.max_body_size)
+            .field("padded", &self.padded)
+            .field("boxes", &self.stats.boxes)
+            .field("closed", &self.is_closed())
+            .field("failed", &self.failed)
+            .finish_non_exhaustive()
     }
 }
 
@@ -79,82 +337,409 @@ enum State {
     Done,
 }
 
-impl<R: AsyncRead, B> AsyncRead for BoxReader<R, B>
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Ready { body_size, pos } => f
+                .debug_struct("Ready")
+                .field("body_size", body_size)
+                .field("pos", pos)
+                .finish(),
+            State::ReadingHead { pos, .. } => {
+                f.debug_struct("ReadingHead").field("pos", pos).finish()
+            }
+            State::ReadingBody { head, pos } => f
+                .debug_struct("ReadingBody")
+                .field("body_size", &head.body_size.get())
+                .field("pos", pos)
+                .finish(),
+            State::Done => f.write_str("Done"),
+        }
+    }
+}
+
+impl<R, B, O> BoxReader<R, B, O>
 where
     R: Unpin + AsyncRead + 'static,
     B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
 {
-    fn poll_read(
-        self: Pin<&mut Self>,
+    /// Wait until there's decrypted data ready to be read, without consuming any of it.
+    /// Resolves to `false` if the peer has said goodbye.
+    pub fn poll_has_data(&mut self, cx: &mut Context) -> Poll<Result<bool, io::Error>> {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+
+        ready!(self.poll_fill(cx))?;
+        Poll::Ready(Ok(!self.is_closed()))
+    }
+
+    // Read and open boxes until there's some plaintext in the buffer,
+    // or the goodbye has been received.
+    fn poll_fill(&mut self, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        self.poll_fill_into(cx, &mut []).map_ok(|_| ())
+    }
+
+    // Like `poll_fill`, except that if `out` is big enough to hold the next body,
+    // the body is read and opened right there instead of in our own buffer.
+    // Returns the number of plaintext bytes that were put in `out`.
+    fn poll_fill_into(
+        &mut self,
         cx: &mut Context,
         out: &mut [u8],
     ) -> Poll<Result<usize, io::Error>> {
-        let mut this = self.get_mut();
+        loop {
+            match self.state {
+                // Handed out whole by `poll_read_box`.
+                State::Ready { body_size, pos } if pos == body_size => self.next_box(),
 
-        match this.state {
-            State::Ready { body_size, pos } => {
-                let n = min(out.len(), body_size - pos);
-                out[..n].copy_from_slice(&this.buffer.as_mut()[pos..pos + n]);
-                if pos + n == body_size {
-                    // need to read a new box
-                    this.state = State::ReadingHead {
-                        head: [0; Head::SIZE],
-                        pos: 0,
-                    };
-                } else {
-                    this.state = State::Ready {
-                        body_size,
-                        pos: pos + n,
+                State::Ready { .. } | State::Done => return Poll::Ready(Ok(0)),
+
+                State::ReadingHead { .. } => ready!(self.poll_head(cx))?,
+
+                State::ReadingBody { head, pos } => {
+                    let body_size = head.body_size.get() as usize;
+                    let direct = pos == 0 && body_size > 0 && out.len() >= body_size;
+                    // A compressed body has to be inflated somewhere else anyway.
+                    #[cfg(feature = "flate2")]
+                    let direct = direct && self.inflater.is_none();
+                    if direct {
+                        let out = &mut out[..body_size];
+                        let n = ready!(self.read_ahead.poll_read(&mut self.inner, cx, out))
+                            .map_err(inner_error)?;
+                        self.stats.ciphertext_bytes += n as u64;
+                        if n == body_size {
+                            let opened = self.opener.open_body(&Header(head), out);
+                            let nonce = self.opener.position() - 1;
+                            if let Err(err) = self.body_opened(opened, nonce) {
+                                // Leave nothing unauthenticated in the caller's buffer.
+                                out.fill(0);
+                                return Poll::Ready(Err(err));
+                            }
+                            let len = plaintext_len(self.padded, out)?;
+                            self.box_opened(len, nonce);
+                            self.next_box();
+                            if len > 0 {
+                                return Poll::Ready(Ok(len));
+                            }
+                            continue;
+                        }
+                        // `out` might not be there next time; keep what we got.
+                        self.buffer.as_mut()[..n].copy_from_slice(&out[..n]);
+                        if n == 0 {
+                            return Poll::Ready(Err(self.unexpected_eof()));
+                        }
+                        self.state = State::ReadingBody { head, pos: n };
+                        continue;
                     }
+
+                    ready!(self.poll_body(cx))?;
                 }
-                Poll::Ready(Ok(n))
             }
+        }
+    }
 
-            State::ReadingHead { mut head, pos } => {
-                let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut head[pos..]))?;
-                if n == head.len() - pos {
-                    // done reading head
-                    let hd = cast_mut::<Head>(&mut head[..])
-                        .open(&this.key, this.nonces.next())
-                        .ok_or(io::Error::from(BoxStreamError::HeaderOpenFailed))?;
-
-                    if hd.is_goodbye() {
-                        this.state = State::Done;
-                        Poll::Ready(Ok(0))
-                    } else {
-                        this.state = State::ReadingBody { head: *hd, pos: 0 };
-                        Pin::new(&mut this).poll_read(cx, out)
-                    }
-                } else {
-                    this.state = State::ReadingHead { head, pos: pos + n };
-                    Poll::Pending
+    // Read the rest of the current body into our buffer, and open it.
+    // Resolves to the length of its plaintext; if that's zero, the reader
+    // has moved on to the next box.
+    fn poll_body(&mut self, cx: &mut Context) -> Poll<Result<usize, io::Error>> {
+        // Carry on with a body that `read_box_bytes` didn't finish.
+        #[cfg(feature = "bytes")]
+        if let State::ReadingBody { pos, .. } = self.state {
+            if !self.bytes.is_empty() {
+                self.buffer.as_mut()[..pos].copy_from_slice(&self.bytes[..pos]);
+                self.bytes.clear();
+            }
+        }
+        while let State::ReadingBody { head, pos } = self.state {
+            let body_size = head.body_size.get() as usize;
+            if pos < body_size {
+                let n = ready!(self.read_ahead.poll_read(
+                    &mut self.inner,
+                    cx,
+                    &mut self.buffer.as_mut()[pos..body_size]
+                ))
+                .map_err(inner_error)?;
+                self.stats.ciphertext_bytes += n as u64;
+                if n == 0 {
+                    return Poll::Ready(Err(self.unexpected_eof()));
                 }
+                self.state = State::ReadingBody { head, pos: pos + n };
+                continue;
             }
 
-            State::ReadingBody { head, pos } => {
-                let body_size = head.body_size.get() as usize;
-                let n = ready!(Pin::new(&mut this.inner)
-                    .poll_read(cx, &mut this.buffer.as_mut()[pos..body_size]))?;
-
-                if n == body_size - pos {
-                    // Done reading body, open it.
-                    if this.key.open(
-                        &mut this.buffer.as_mut()[..body_size],
-                        &head.body_hmac,
-                        &this.nonces.next(),
-                    ) {
-                        this.state = State::Ready { body_size, pos: 0 };
-                        Pin::new(&mut this).poll_read(cx, out)
-                    } else {
-                        Poll::Ready(Err(BoxStreamError::BodyOpenFailed.into()))
-                    }
-                } else {
-                    this.state = State::ReadingBody { head, pos: pos + n };
-                    Poll::Pending
+            // Done reading body, open it.
+            let opened = self
+                .opener
+                .open_body(&Header(head), &mut self.buffer.as_mut()[..body_size]);
+            let nonce = self.opener.position() - 1;
+            self.body_opened(opened, nonce)?;
+            let len = plaintext_len(self.padded, &self.buffer.as_mut()[..body_size])?;
+            #[cfg(feature = "flate2")]
+            let len = match &mut self.inflater {
+                Some(inflater) if len > 0 => inflate(
+                    inflater,
+                    &self.buffer.as_mut()[..len],
+                    self.max_inflated_size,
+                )?,
+                _ => len,
+            };
+            self.box_opened(len, nonce);
+
+            // An empty box carries nothing to read; move on to the next one.
+            if len == 0 {
+                self.next_box();
+            } else {
+                self.state = State::Ready {
+                    body_size: len,
+                    pos: 0,
+                };
+            }
+            return Poll::Ready(Ok(len));
+        }
+        Poll::Ready(Ok(0))
+    }
+
+    // Make progress on reading the current header, and open it once it's all here.
+    fn poll_head(&mut self, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        if let State::ReadingHead { mut head, pos } = self.state {
+            let n = ready!(self
+                .read_ahead
+                .poll_read(&mut self.inner, cx, &mut head[pos..]))
+            .map_err(inner_error)?;
+            self.stats.ciphertext_bytes += n as u64;
+            if n == 0 {
+                return Poll::Ready(Err(self.unexpected_eof()));
+            }
+            if n < head.len() - pos {
+                self.state = State::ReadingHead { head, pos: pos + n };
+                return Poll::Ready(Ok(()));
+            }
+
+            // done reading head
+            match self.opener.open_header(&head) {
+                Err(err) => {
+                    warn!(
+                        nonce = self.opener.position() - 1,
+                        offset = self.stats.ciphertext_bytes,
+                        "failed to decrypt header"
+                    );
+                    self.observer.on_auth_failure(self.stats.boxes);
+                    self.failed = true;
+                    return Poll::Ready(Err(BoxStreamError::from(err).into()));
                 }
+                Ok(None) => {
+                    debug!(boxes = self.stats.boxes, "goodbye received");
+                    self.observer.on_goodbye_received();
+                    self.stats.goodbye = true;
+                    self.state = State::Done;
+                    (self.release)(&mut self.buffer);
+                }
+                Ok(Some(Header(hd))) => self.header_opened(hd)?,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Get ready to read the body that follows an opened header.
+    fn header_opened(&mut self, hd: HeadPayload) -> Result<(), io::Error> {
+        let size = hd.body_size.get() as usize;
+        if size > self.max_body_size {
+            warn!(
+                index = self.stats.boxes,
+                size,
+                max = self.max_body_size,
+                "box body too large"
+            );
+            self.failed = true;
+            let max = self.max_body_size;
+            return Err(BoxStreamError::from(DecodeError::BodyTooLarge { size, max }).into());
+        }
+        self.sealed_size = size;
+        self.state = State::ReadingBody { head: hd, pos: 0 };
+        Ok(())
+    }
+
+    // The inner reader ended before the goodbye.
+    fn unexpected_eof(&mut self) -> io::Error {
+        warn!(boxes = self.stats.boxes, "stream ended before the goodbye");
+        self.stats.truncated = true;
+        io::ErrorKind::UnexpectedEof.into()
+    }
+
+    // Get ready to read the next box. The buffer isn't needed until its body arrives.
+    fn next_box(&mut self) {
+        self.state = State::ReadingHead {
+            head: [0; Head::SIZE],
+            pos: 0,
+        };
+        (self.release)(&mut self.buffer);
+    }
+
+    // The opened body that `State::Ready` refers to: the inflated copy when
+    // compression is on, or else our buffer.
+    fn plaintext(&mut self) -> &mut [u8] {
+        #[cfg(feature = "flate2")]
+        if let Some(inflater) = &mut self.inflater {
+            return &mut inflater.out;
+        }
+        self.buffer.as_mut()
+    }
+
+    // Mark `n` bytes of the opened body as read.
+    fn consume_plaintext(&mut self, n: usize) {
+        if let State::Ready { body_size, pos } = self.state {
+            if pos + n >= body_size {
+                self.next_box();
+            } else {
+                self.state = State::Ready {
+                    body_size,
+                    pos: pos + n,
+                };
             }
+        }
+    }
 
-            State::Done => Poll::Ready(Ok(0)),
+    // Bookkeeping after an attempt to open a box body.
+    // `nonce` is the position of the body's nonce in the series.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn body_opened(
+        &mut self,
+        opened: Result<(), DecodeError>,
+        nonce: u64,
+    ) -> Result<(), io::Error> {
+        if let Err(err) = opened {
+            warn!(
+                index = self.stats.boxes,
+                nonce,
+                offset = self.stats.ciphertext_bytes,
+                "failed to decrypt body"
+            );
+            self.observer.on_auth_failure(self.stats.boxes);
+            self.failed = true;
+            return Err(BoxStreamError::from(err).into());
         }
+        Ok(())
+    }
+
+    // Bookkeeping once an opened body has been decoded. `len` is the size
+    // of its plaintext, after padding and compression have been taken off.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn box_opened(&mut self, len: usize, nonce: u64) {
+        trace!(index = self.stats.boxes, len, nonce, "box opened");
+        self.observer.on_box_opened(self.stats.boxes, len);
+        self.stats.record_box(len);
+    }
+}
+
+// The length of the plaintext in an opened body.
+fn plaintext_len(padded: bool, body: &[u8]) -> Result<usize, io::Error> {
+    if !padded {
+        return Ok(body.len());
+    }
+    unpadded_len(body).ok_or_else(|| {
+        warn!(size = body.len(), "box body isn't padded");
+        BoxStreamError::BadPadding.into()
+    })
+}
+
+// Decompress an opened body into the inflater.
+#[cfg(feature = "flate2")]
+fn inflate(inflater: &mut Inflater, body: &[u8], max: usize) -> Result<usize, io::Error> {
+    inflater.inflate(body, max).map_err(|err| {
+        warn!(size = body.len(), max, "failed to inflate box body");
+        match err {
+            InflateError::Corrupt => BoxStreamError::InflateFailed,
+            InflateError::TooLarge => BoxStreamError::InflatedTooLarge { max },
+        }
+        .into()
+    })
+}
+
+impl<R, B, O> AsyncRead for BoxReader<R, B, O>
+where
+    R: Unpin + AsyncRead + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        out: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        #[cfg(feature = "tracing")]
+        let _span = this.span.clone().entered();
+
+        let n = ready!(this.poll_fill_into(cx, out))?;
+        if n > 0 {
+            return Poll::Ready(Ok(n));
+        }
+        match this.state {
+            State::Ready { body_size, pos } => {
+                let n = min(out.len(), body_size - pos);
+                out[..n].copy_from_slice(&this.plaintext()[pos..pos + n]);
+                this.consume_plaintext(n);
+                Poll::Ready(Ok(n))
+            }
+            _ => Poll::Ready(Ok(0)),
+        }
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        #[cfg(feature = "tracing")]
+        let _span = this.span.clone().entered();
+
+        let mut bufs = bufs.iter_mut().filter(|b| !b.is_empty());
+        let first = match bufs.next() {
+            Some(b) => b,
+            None => return Poll::Ready(Ok(0)),
+        };
+        let n = ready!(this.poll_fill_into(cx, first))?;
+        if n > 0 {
+            return Poll::Ready(Ok(n));
+        }
+
+        // Copy out as much of the current box as fits.
+        let mut total = 0;
+        for out in core::iter::once(first).chain(bufs) {
+            let (body_size, pos) = match this.state {
+                State::Ready { body_size, pos } => (body_size, pos),
+                _ => break,
+            };
+            let n = min(out.len(), body_size - pos);
+            out[..n].copy_from_slice(&this.plaintext()[pos..pos + n]);
+            this.consume_plaintext(n);
+            total += n;
+        }
+        Poll::Ready(Ok(total))
+    }
+}
+
+impl<R, B, O> AsyncBufRead for BoxReader<R, B, O>
+where
+    R: Unpin + AsyncRead + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<&[u8], io::Error>> {
+        let this = self.get_mut();
+        #[cfg(feature = "tracing")]
+        let _span = this.span.clone().entered();
+
+        ready!(this.poll_fill(cx))?;
+        match this.state {
+            State::Ready { body_size, pos } => Poll::Ready(Ok(&this.plaintext()[pos..body_size])),
+            _ => Poll::Ready(Ok(&[])),
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().consume_plaintext(amt)
     }
 }
@@ -0,0 +1,124 @@
+use super::*;
+use ::bytes::Bytes;
+use core::cmp::max;
+
+// How much `read_box_bytes` allocates at a time, to be shared by several bodies.
+const BYTES_CHUNK: usize = 16 * MAX_BOX_SIZE;
+
+impl<R, B, O> BoxReader<R, B, O>
+where
+    R: Unpin + AsyncRead + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Read the next box, and resolve to its body. The body is read and opened
+    /// in a `BytesMut` that the reader keeps, and split off from it, so it's
+    /// handed over without being copied (unless compression is on, in which
+    /// case the inflated body is). Bodies share allocations of up to 64KiB,
+    /// which are reused once the `Bytes` taken from them are dropped.
+    /// Resolves to an empty `Bytes` once the peer has said goodbye.
+    ///
+    /// Plaintext that's already waiting in the reader's buffer (say, after a
+    /// short `read`) is copied out instead.
+    ///
+    /// The future doesn't hold anything itself: a body that's partly read is
+    /// kept in the reader, so it can be dropped at any point, and the next
+    /// read carries on where it left off.
+    pub fn read_box_bytes(&mut self) -> ReadBoxBytes<'_, R, B, O> {
+        ReadBoxBytes { reader: self }
+    }
+}
+
+/// Future returned by [`BoxReader::read_box_bytes`](./struct.BoxReader.html#method.read_box_bytes).
+pub struct ReadBoxBytes<'a, R, B, O> {
+    reader: &'a mut BoxReader<R, B, O>,
+}
+
+impl<R, B, O> Future for ReadBoxBytes<'_, R, B, O>
+where
+    R: Unpin + AsyncRead + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    type Output = Result<Bytes, io::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let r = &mut *self.get_mut().reader;
+        #[cfg(feature = "tracing")]
+        let _span = r.span.clone().entered();
+
+        loop {
+            match r.state {
+                State::Done => return Poll::Ready(Ok(Bytes::new())),
+
+                State::Ready { body_size, pos } if pos == body_size => r.next_box(),
+
+                State::Ready { body_size, pos } => {
+                    let out = Bytes::copy_from_slice(&r.plaintext()[pos..body_size]);
+                    r.consume_plaintext(body_size - pos);
+                    return Poll::Ready(Ok(out));
+                }
+
+                State::ReadingHead { .. } => ready!(r.poll_head(cx))?,
+
+                // Part of this body was read into our buffer by something else.
+                State::ReadingBody { pos, .. } if pos > 0 && r.bytes.is_empty() => {
+                    ready!(r.poll_fill(cx))?;
+                }
+
+                State::ReadingBody { head, pos } => {
+                    let body_size = head.body_size.get() as usize;
+                    if pos == 0 {
+                        if r.bytes.capacity() < body_size {
+                            r.bytes.reserve(max(body_size, BYTES_CHUNK));
+                        }
+                        r.bytes.resize(body_size, 0);
+                    }
+
+                    if pos < body_size {
+                        let read = r
+                            .read_ahead
+                            .poll_read(&mut r.inner, cx, &mut r.bytes[pos..]);
+                        let n = match read {
+                            Poll::Ready(Ok(n)) => n,
+                            // `bytes` only holds on to a body that's been started.
+                            p => {
+                                if pos == 0 {
+                                    r.bytes.clear();
+                                }
+                                return p.map_ok(|_| Bytes::new()).map_err(inner_error);
+                            }
+                        };
+                        r.stats.ciphertext_bytes += n as u64;
+                        if n == 0 {
+                            r.bytes.clear();
+                            return Poll::Ready(Err(r.unexpected_eof()));
+                        }
+                        r.state = State::ReadingBody { head, pos: pos + n };
+                        continue;
+                    }
+
+                    let mut body = r.bytes.split_to(body_size);
+                    let opened = r.opener.open_body(&Header(head), &mut body);
+                    let nonce = r.opener.position() - 1;
+                    r.body_opened(opened, nonce)?;
+                    let len = plaintext_len(r.padded, &body)?;
+                    body.truncate(len);
+                    #[cfg(feature = "flate2")]
+                    if let Some(inflater) = &mut r.inflater {
+                        if len > 0 {
+                            inflate(inflater, &body, r.max_inflated_size)?;
+                            body = ::bytes::BytesMut::from(&inflater.out[..]);
+                        }
+                    }
+                    r.box_opened(body.len(), nonce);
+                    r.next_box();
+                    // Empty boxes aren't handed out.
+                    if !body.is_empty() {
+                        return Poll::Ready(Ok(body.freeze()));
+                    }
+                }
+            }
+        }
+    }
+}
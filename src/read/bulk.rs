@@ -0,0 +1,209 @@
+use super::*;
+use crate::codec::open_body_with;
+use rayon::prelude::*;
+
+impl<R, B, O> BoxReader<R, B, O>
+where
+    R: Unpin + AsyncRead + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Read a batch of boxes, and open their bodies in parallel on the rayon
+    /// thread pool. Resolves to the plaintext, which is empty once the peer
+    /// has said goodbye.
+    ///
+    /// Boxes are read until at least `max_len` bytes have been collected, or until
+    /// the inner reader has nothing more to give right now. The last box can take
+    /// the plaintext past `max_len`. Any plaintext already sitting in the reader's
+    /// buffer comes first.
+    ///
+    /// With [`set_compressed`](#method.set_compressed) on, `max_len` counts
+    /// bodies as they are on the wire, before they're inflated, so the plaintext
+    /// can be many times bigger. Each body inflates to at most
+    /// [`set_max_inflated_size`](#method.set_max_inflated_size) bytes.
+    ///
+    /// The future doesn't hold on to anything while it waits: a body that's
+    /// partly read is kept in the reader's buffer, and boxes that have been read
+    /// in full are opened and returned. So it can be dropped at any point, and
+    /// the next read carries on where it left off.
+    pub fn read_bulk(&mut self, max_len: usize) -> ReadBulk<'_, R, B, O> {
+        ReadBulk {
+            reader: self,
+            max_len,
+        }
+    }
+}
+
+/// Future returned by [`BoxReader::read_bulk`](./struct.BoxReader.html#method.read_bulk).
+pub struct ReadBulk<'a, R, B, O> {
+    reader: &'a mut BoxReader<R, B, O>,
+    max_len: usize,
+}
+
+struct SealedBody {
+    start: usize,
+    size: usize,
+    header: Header,
+    nonce: Nonce,
+    nonce_pos: u64,
+}
+
+impl<R, B, O> Future for ReadBulk<'_, R, B, O>
+where
+    R: Unpin + AsyncRead + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    type Output = Result<Vec<u8>, io::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let r = &mut *this.reader;
+        #[cfg(feature = "tracing")]
+        let _span = r.span.clone().entered();
+
+        let mut out = Vec::new();
+        let mut sealed = Vec::new();
+        // Whether we stopped because the inner reader isn't ready.
+        let mut waiting = false;
+
+        loop {
+            match r.state {
+                State::Done => break,
+
+                State::Ready { body_size, pos } => {
+                    out.extend_from_slice(&r.plaintext()[pos..body_size]);
+                    r.consume_plaintext(body_size - pos);
+                }
+
+                // A body that was partly read into our own buffer;
+                // finish it the usual way.
+                State::ReadingBody { pos, .. } if pos > 0 => match r.poll_fill(cx) {
+                    Poll::Ready(res) => res?,
+                    Poll::Pending => {
+                        waiting = true;
+                        break;
+                    }
+                },
+
+                State::ReadingHead { .. } => {
+                    if out.len() >= this.max_len {
+                        break;
+                    }
+                    match r.poll_head(cx) {
+                        Poll::Ready(res) => res?,
+                        Poll::Pending => {
+                            waiting = true;
+                            break;
+                        }
+                    }
+                }
+
+                State::ReadingBody { head, .. } => {
+                    let body_size = head.body_size.get() as usize;
+                    let start = out.len();
+                    out.resize(start + body_size, 0);
+
+                    let mut pos = 0;
+                    while pos < body_size {
+                        let dst = &mut out[start + pos..];
+                        let n = match r.read_ahead.poll_read(&mut r.inner, cx, dst) {
+                            Poll::Ready(res) => res.map_err(inner_error)?,
+                            Poll::Pending => break,
+                        };
+                        r.stats.ciphertext_bytes += n as u64;
+                        if n == 0 {
+                            return Poll::Ready(Err(r.unexpected_eof()));
+                        }
+                        pos += n;
+                    }
+                    if pos < body_size {
+                        // Keep what we got in our own buffer, for the next read.
+                        r.buffer.as_mut()[..pos].copy_from_slice(&out[start..start + pos]);
+                        r.state = State::ReadingBody { head, pos };
+                        out.truncate(start);
+                        waiting = true;
+                        break;
+                    }
+
+                    sealed.push(SealedBody {
+                        start,
+                        size: body_size,
+                        header: Header(head),
+                        nonce: r.opener.next_nonce(),
+                        nonce_pos: r.opener.position() - 1,
+                    });
+                    r.next_box();
+                }
+            }
+        }
+
+        // Split the output into the individual bodies, and open them all at once.
+        let mut rest = &mut out[..];
+        let mut offset = 0;
+        let mut bodies = Vec::with_capacity(sealed.len());
+        for s in &sealed {
+            let (_, tail) = rest.split_at_mut(s.start - offset);
+            let (body, tail) = tail.split_at_mut(s.size);
+            bodies.push(body);
+            rest = tail;
+            offset = s.start + s.size;
+        }
+        let key = r.opener.key();
+        let opened: Vec<Result<(), DecodeError>> = bodies
+            .into_par_iter()
+            .zip(sealed.par_iter())
+            .map(|(body, s)| open_body_with(key, &s.header, &s.nonce, body))
+            .collect();
+
+        for (opened, s) in opened.into_iter().zip(sealed.iter()) {
+            r.body_opened(opened, s.nonce_pos)?;
+        }
+        // The plaintext sizes of the bodies, once they've been decoded.
+        let mut lens: Vec<(usize, u64)> = sealed.iter().map(|s| (s.size, s.nonce_pos)).collect();
+
+        // Compressed bodies are inflated one after another, into a new output.
+        #[cfg(feature = "flate2")]
+        if let Some(inflater) = &mut r.inflater {
+            let mut inflated = Vec::with_capacity(out.len());
+            let mut from = 0;
+            for (s, (len, _)) in sealed.iter().zip(&mut lens) {
+                inflated.extend_from_slice(&out[from..s.start]);
+                let body = &out[s.start..s.start + s.size];
+                *len = plaintext_len(r.padded, body)?;
+                if *len > 0 {
+                    *len = inflate(inflater, &body[..*len], r.max_inflated_size)?;
+                    inflated.extend_from_slice(&inflater.out);
+                }
+                from = s.start + s.size;
+            }
+            inflated.extend_from_slice(&out[from..]);
+            out = inflated;
+            // The padding's gone already.
+            sealed.clear();
+        }
+
+        // Take the padding out of the bodies, and close up the gaps it leaves.
+        if r.padded {
+            let (mut end, mut from) = (0, 0);
+            for (s, (len, _)) in sealed.iter().zip(&mut lens) {
+                *len = plaintext_len(true, &out[s.start..s.start + s.size])?;
+                out.copy_within(from..s.start + *len, end);
+                end += s.start + *len - from;
+                from = s.start + s.size;
+            }
+            out.copy_within(from.., end);
+            out.truncate(end + out.len() - from);
+        }
+
+        for (len, nonce) in lens {
+            r.box_opened(len, nonce);
+        }
+
+        // Empty boxes don't count as something to return.
+        if out.is_empty() && waiting {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(out))
+    }
+}
@@ -0,0 +1,85 @@
+use super::*;
+
+/// One box, as returned by [`BoxReader::poll_read_box`](./struct.BoxReader.html#method.poll_read_box).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoxFrame<'a> {
+    /// The decrypted body. If some of it was already read through `AsyncRead`,
+    /// this is just the rest.
+    pub body: &'a [u8],
+    /// The number of boxes that came before this one.
+    pub index: u64,
+    /// The position in the nonce series of the nonce that opened the body
+    /// (or, for the goodbye, the header).
+    pub nonce: u64,
+    /// The size of the box on the wire, header and any padding included.
+    pub ciphertext_len: usize,
+    /// Whether this is the goodbye, which ends the stream.
+    pub goodbye: bool,
+}
+
+impl<R, B, O> BoxReader<R, B, O>
+where
+    R: Unpin + AsyncRead + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Read the next box, and return its body along with where it sits in the
+    /// stream. The body counts as read, so it won't be returned by `AsyncRead` too,
+    /// but the two can otherwise be mixed freely.
+    ///
+    /// Unlike `AsyncRead`, empty boxes are returned. Once the peer has said
+    /// goodbye, every call returns the goodbye frame.
+    pub fn poll_read_box(&mut self, cx: &mut Context) -> Poll<Result<BoxFrame<'_>, io::Error>> {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+
+        loop {
+            match self.state {
+                State::Done => {
+                    return Poll::Ready(Ok(BoxFrame {
+                        body: &[],
+                        index: self.stats.boxes,
+                        nonce: self.opener.position() - 1,
+                        ciphertext_len: Head::SIZE,
+                        goodbye: true,
+                    }))
+                }
+
+                State::Ready { body_size, pos } if pos == body_size => self.next_box(),
+
+                // Leave the body in the buffer until the next call,
+                // and mark it as read so nothing else hands it out.
+                State::Ready { body_size, pos } => {
+                    self.state = State::Ready {
+                        body_size,
+                        pos: body_size,
+                    };
+                    let (index, nonce) = (self.stats.boxes - 1, self.opener.position() - 1);
+                    let ciphertext_len = Head::SIZE + self.sealed_size;
+                    return Poll::Ready(Ok(BoxFrame {
+                        body: &self.plaintext()[pos..body_size],
+                        index,
+                        nonce,
+                        ciphertext_len,
+                        goodbye: false,
+                    }));
+                }
+
+                State::ReadingHead { .. } => ready!(self.poll_head(cx))?,
+
+                // `poll_fill` would skip a box with nothing in it.
+                State::ReadingBody { .. } => {
+                    if ready!(self.poll_body(cx))? == 0 {
+                        return Poll::Ready(Ok(BoxFrame {
+                            body: &[],
+                            index: self.stats.boxes - 1,
+                            nonce: self.opener.position() - 1,
+                            ciphertext_len: Head::SIZE + self.sealed_size,
+                            goodbye: false,
+                        }));
+                    }
+                }
+            }
+        }
+    }
+}
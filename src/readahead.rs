@@ -0,0 +1,58 @@
+use core::cmp::min;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_core::ready;
+use futures_io::{self as io, AsyncRead};
+
+/// Ciphertext that has been read from the inner reader, but not yet decoded.
+/// With a read-ahead size of zero (the default), reads go straight to the inner reader.
+#[derive(Default)]
+pub(crate) struct ReadAhead {
+    size: usize,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl ReadAhead {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of bytes that have been read ahead, but not yet consumed.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn set_size(&mut self, size: usize) {
+        // Keep any bytes we've already read.
+        let mut buf = vec![0; size.max(self.len())];
+        buf[..self.len()].copy_from_slice(&self.buf[self.start..self.end]);
+        self.end = self.len();
+        self.start = 0;
+        self.buf = buf;
+        self.size = size;
+    }
+
+    /// Read into `dst`, refilling the read-ahead buffer from `inner` if it's empty.
+    pub fn poll_read<R: AsyncRead + Unpin>(
+        &mut self,
+        inner: &mut R,
+        cx: &mut Context,
+        dst: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        if self.start == self.end {
+            if self.size == 0 || dst.len() >= self.size {
+                return Pin::new(inner).poll_read(cx, dst);
+            }
+            let n = ready!(Pin::new(inner).poll_read(cx, &mut self.buf[..]))?;
+            self.start = 0;
+            self.end = n;
+        }
+
+        let n = min(dst.len(), self.len());
+        dst[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+        self.start += n;
+        Poll::Ready(Ok(n))
+    }
+}
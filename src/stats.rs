@@ -0,0 +1,94 @@
+/// Traffic counters for one direction of a box stream.
+///
+/// Counting is just a handful of integer additions per box, so it's always on.
+/// Get a snapshot with [`BoxReader::stats`](./struct.BoxReader.html#method.stats),
+/// [`BoxWriter::stats`](./struct.BoxWriter.html#method.stats) or
+/// [`BoxStream::stats`](./struct.BoxStream.html#method.stats).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of boxes sealed or opened, not counting the goodbye.
+    pub boxes: u64,
+    /// Plaintext bytes carried in box bodies. Padding isn't counted, and
+    /// compressed bodies count the bytes before compression.
+    pub plaintext_bytes: u64,
+    /// Bytes written to or read from the inner stream, headers included.
+    pub ciphertext_bytes: u64,
+    /// Number of completed flushes of the inner writer. Always zero for readers.
+    pub flushes: u64,
+    /// Distribution of the plaintext sizes of box bodies, counted the same
+    /// way as `plaintext_bytes`.
+    pub body_sizes: SizeHistogram,
+    /// Whether the goodbye header has been sent (writer) or received (reader).
+    pub goodbye: bool,
+    /// Whether the inner reader ended before the goodbye was received.
+    /// Always false for writers.
+    pub truncated: bool,
+}
+
+impl Stats {
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn record_box(&mut self, body_size: usize) {
+        self.boxes += 1;
+        self.plaintext_bytes += body_size as u64;
+        self.body_sizes.record(body_size);
+    }
+}
+
+/// Stats for both directions of a [`BoxStream`](./struct.BoxStream.html).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    pub read: Stats,
+    pub write: Stats,
+}
+
+/// Histogram of box body sizes, in power-of-two buckets.
+///
+/// Bucket 0 counts bodies of up to 16 bytes, bucket 1 up to 32 bytes, and so on
+/// up to bucket 12, which counts bodies of up to 65536 bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    counts: [u64; SizeHistogram::BUCKETS],
+}
+
+impl SizeHistogram {
+    pub const BUCKETS: usize = 13;
+
+    /// The largest body size counted in the given bucket.
+    pub fn upper_bound(bucket: usize) -> usize {
+        16 << bucket
+    }
+
+    /// Counts for each bucket, smallest sizes first.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Iterate over `(upper_bound, count)` pairs, smallest sizes first.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (Self::upper_bound(i), *c))
+    }
+
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    fn record(&mut self, size: usize) {
+        let bucket = size.max(1).next_power_of_two().trailing_zeros() as usize;
+        let i = bucket.saturating_sub(4).min(Self::BUCKETS - 1);
+        self.counts[i] += 1;
+    }
+}
+
+#[test]
+fn histogram_buckets() {
+    let mut h = SizeHistogram::default();
+    for size in &[0, 1, 16, 17, 4096, 4097, 65535] {
+        h.record(*size);
+    }
+    assert_eq!(h.counts()[0], 3);
+    assert_eq!(h.counts()[1], 1);
+    assert_eq!(h.counts()[8], 1);
+    assert_eq!(h.counts()[9], 1);
+    assert_eq!(h.counts()[12], 1);
+    assert_eq!(h.iter().last(), Some((65536, 1)));
+}
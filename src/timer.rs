@@ -0,0 +1,42 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::time::Duration;
+
+/// A future that resolves once a [`Timer`](./trait.Timer.html)'s delay has passed.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A source of delays, so that time-based behaviour isn't tied to one runtime.
+///
+/// Implementations for tokio and async-io are available behind the `tokio`
+/// and `async-io` features.
+pub trait Timer {
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// A [`Timer`](./trait.Timer.html) backed by `tokio::time`.
+/// Must be used from within a tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A [`Timer`](./trait.Timer.html) backed by `async_io::Timer`.
+#[cfg(feature = "async-io")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncIoTimer;
+
+#[cfg(feature = "async-io")]
+impl Timer for AsyncIoTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let timer = async_io::Timer::after(duration);
+        Box::pin(async move {
+            timer.await;
+        })
+    }
+}
@@ -0,0 +1,37 @@
+//! Thin wrappers around the `tracing` macros, so that call sites don't need
+//! to be littered with `cfg` attributes. Without the `tracing` feature the
+//! macros expand to nothing, and their arguments aren't evaluated.
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($t:tt)*) => { tracing::trace!($($t)*) };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($t:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($t:tt)*) => { tracing::debug!($($t)*) };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($t:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($t:tt)*) => { tracing::warn!($($t)*) };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($t:tt)*) => {};
+}
+
+/// Record an error returned by the inner reader or writer.
+#[cfg(feature = "std")]
+pub(crate) fn inner_error(err: futures_io::Error) -> futures_io::Error {
+    debug!(error = %err, "inner transport error");
+    err
+}
@@ -1,53 +1,398 @@
+use crate::codec::{BoxSealer, BoxSize, MAX_BOX_SIZE, MAX_EXTENDED_BOX_SIZE};
 use crate::msg::*;
-use crate::NonceGen;
+use crate::observer::BoxObserver;
+use crate::padding::{pad, pad_in_place, Padding};
+use crate::pool::{BufferProvider, Pooled};
+use crate::stats::Stats;
+use crate::trace::inner_error;
 use core::cmp::min;
+use core::fmt;
+use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
 use futures_core::ready;
-use futures_io::{AsyncWrite, Error};
+use futures_io::{AsyncWrite, Error, ErrorKind, IoSlice};
 use ssb_crypto::secretbox::{Key, Nonce};
+use std::collections::VecDeque;
 
-pub const MAX_BOX_SIZE: usize = 4096;
+#[cfg(feature = "bytes")]
+mod buf;
+#[cfg(feature = "bytes")]
+pub use buf::*;
+#[cfg(feature = "rayon")]
+mod bulk;
+mod cover;
+mod encoding;
+mod flush;
+#[cfg(feature = "rayon")]
+pub use bulk::*;
+use cover::Cover;
+pub use cover::CoverTraffic;
+use encoding::Encoding;
+use flush::AutoFlush;
+pub use flush::FlushPolicy;
 
-pub(crate) fn seal(mut body: &mut [u8], key: &Key, noncegen: &mut NonceGen) -> Head {
-    let head_nonce = noncegen.next();
-    let body_nonce = noncegen.next();
+// The most boxes that are handed to the inner writer in one vectored write.
+// Only boxes that are already sealed and queued, by `write_bulk`, are sent
+// together; otherwise each write carries one box.
+const MAX_WRITE_BOXES: usize = 32;
 
-    let body_hmac = key.seal(&mut body, &body_nonce);
-    HeadPayload::new(body.len() as u16, body_hmac).seal(&key, head_nonce)
-}
-
-pub struct BoxWriter<W, B> {
+pub struct BoxWriter<W, B, O = ()> {
     inner: W,
     buffer: B,
+    buffer_size: usize,
+    // Called on buffers that aren't holding anything; see `pooled`.
+    release: fn(&mut B),
+    // With double buffering, the buffer that holds the box being sent,
+    // while `buffer` accepts more plaintext.
+    back: Option<B>,
     state: State,
-    key: Key,
-    nonces: NonceGen,
+    // The most plaintext that goes into one box.
+    box_size: usize,
+    strict: bool,
+    sealer: BoxSealer,
+    // A caller's buffer that's being sealed and sent in place; see `write_owned`.
+    owned: Vec<u8>,
+    // Boxes that are sealed and waiting to be sent after the current one, with
+    // the start and size of their bodies in `owned`; see `write_bulk`.
+    queued: VecDeque<(Head, usize, usize)>,
+    encoding: Encoding,
+    // Where bodies are encoded and sealed, when they aren't plain.
+    scratch: Vec<u8>,
+    flush: AutoFlush,
+    cover: Cover,
+    stats: Stats,
+    // The inner writer failed, or stopped taking bytes.
+    failed: bool,
+    observer: O,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
-impl<W, B> BoxWriter<W, B> {
-    pub fn with_buffer(inner: W, key: Key, nonce: Nonce, buffer: B) -> BoxWriter<W, B> {
+impl<W, B: AsMut<[u8]>> BoxWriter<W, B> {
+    /// Create a writer that buffers plaintext in `buffer`. Boxes are as big as
+    /// the buffer, up to [`MAX_BOX_SIZE`](./constant.MAX_BOX_SIZE.html).
+    ///
+    /// A bigger buffer doesn't make bigger boxes unless strict mode is turned off
+    /// and the box size set to match; see [`set_strict`](#method.set_strict) and
+    /// [`set_box_size`](#method.set_box_size). Boxes used to be as big as the
+    /// buffer, however big it was; see the changelog.
+    ///
+    /// # Panics
+    /// Panics if the buffer is empty.
+    pub fn with_buffer(inner: W, key: Key, nonce: Nonce, mut buffer: B) -> BoxWriter<W, B> {
+        let size = buffer.as_mut().len();
+        BoxWriter::with_sized_buffer(inner, key, nonce, buffer, size)
+    }
+
+    fn with_sized_buffer(
+        inner: W,
+        key: Key,
+        nonce: Nonce,
+        buffer: B,
+        buffer_size: usize,
+    ) -> BoxWriter<W, B> {
+        let box_size = min(buffer_size, MAX_BOX_SIZE);
+        assert!(box_size > 0, "buffer is empty");
         BoxWriter {
             inner,
             buffer,
+            buffer_size,
+            release: |_| {},
+            back: None,
             state: State::Buffering { pos: 0 },
-            key,
-            nonces: NonceGen::with_starting_nonce(nonce),
+            box_size,
+            strict: true,
+            sealer: BoxSealer::new(key, nonce),
+            owned: Vec::new(),
+            queued: VecDeque::new(),
+            encoding: Encoding::default(),
+            scratch: Vec::new(),
+            flush: AutoFlush::default(),
+            cover: Cover::default(),
+            stats: Stats::default(),
+            failed: false,
+            observer: (),
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        }
+    }
+
+    /// Create a double-buffered writer. While a sealed box is being sent
+    /// from one buffer, the other keeps accepting plaintext, so writes can
+    /// continue while a slow inner writer drains.
+    ///
+    /// # Panics
+    /// Panics if the buffers aren't the same size.
+    pub fn with_buffers(
+        inner: W,
+        key: Key,
+        nonce: Nonce,
+        mut buffer: B,
+        mut back: B,
+    ) -> BoxWriter<W, B> {
+        assert_eq!(buffer.as_mut().len(), back.as_mut().len());
+        let mut w = BoxWriter::with_buffer(inner, key, nonce, buffer);
+        w.back = Some(back);
+        w
+    }
+}
+
+impl<W, B: AsMut<[u8]>, O> BoxWriter<W, B, O> {
+    /// Set the most plaintext that's put into one box. Smaller boxes get data
+    /// to the peer sooner, at the cost of 34 bytes of header apiece.
+    /// If data is already buffered, the change applies from the next box.
+    ///
+    /// # Panics
+    /// Panics if `size` is zero (or leaves no room for padding or compression)
+    /// or bigger than the buffer, or if it's bigger than
+    /// [`MAX_BOX_SIZE`](./constant.MAX_BOX_SIZE.html) in strict mode.
+    pub fn set_box_size(&mut self, size: usize) {
+        let limit = self.box_size_limit();
+        assert!(size > 0, "box size must not be zero");
+        assert!(
+            size > self.encoding.overhead(size),
+            "box size {} leaves no room for padding or compression",
+            size
+        );
+        assert!(
+            size <= limit,
+            "box size {} is over the limit of {}",
+            size,
+            limit
+        );
+        assert!(
+            size <= self.buffer_size,
+            "box size {} is bigger than the buffer",
+            size
+        );
+        self.box_size = size;
+    }
+
+    /// Strict mode, which is on by default, keeps boxes within the protocol's
+    /// [`MAX_BOX_SIZE`](./constant.MAX_BOX_SIZE.html). With it off, boxes can be
+    /// up to [`MAX_EXTENDED_BOX_SIZE`](./constant.MAX_EXTENDED_BOX_SIZE.html), which
+    /// only readers with a matching [`max_body_size`](./struct.BoxReader.html#method.set_max_body_size)
+    /// will accept. Turning strict mode on shrinks the box size to fit.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+        if strict {
+            self.box_size = min(self.box_size, MAX_BOX_SIZE);
         }
     }
 
+    /// Pad the bodies of boxes, so that their sizes don't give away how much
+    /// was written. **Only use this with a peer that strips the padding**; see
+    /// [`Padding`](./enum.Padding.html). Each box then carries one byte less of
+    /// plaintext than the box size.
+    ///
+    /// # Panics
+    /// Panics if the policy is `Bucket(0)`, or if padding is turned on with a
+    /// box size of one.
+    pub fn set_padding(&mut self, padding: Padding) {
+        assert!(
+            padding != Padding::Bucket(0),
+            "padding bucket must not be zero"
+        );
+        assert!(
+            self.box_size > padding.overhead() + self.encoding.compression_overhead(self.box_size),
+            "box size {} leaves no room for padding",
+            self.box_size
+        );
+        self.encoding.padding = padding;
+    }
+}
+
+impl<W, B, O> BoxWriter<W, B, O> {
+    /// Replace the writer's observer, which is notified of box-level events.
+    pub fn with_observer<P: BoxObserver>(self, observer: P) -> BoxWriter<W, B, P> {
+        BoxWriter {
+            inner: self.inner,
+            buffer: self.buffer,
+            buffer_size: self.buffer_size,
+            release: self.release,
+            back: self.back,
+            state: self.state,
+            box_size: self.box_size,
+            strict: self.strict,
+            sealer: self.sealer,
+            owned: self.owned,
+            queued: self.queued,
+            encoding: self.encoding,
+            scratch: self.scratch,
+            flush: self.flush,
+            cover: self.cover,
+            stats: self.stats,
+            failed: self.failed,
+            observer,
+            #[cfg(feature = "tracing")]
+            span: self.span,
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Record this writer's events inside the given span.
+    #[cfg(feature = "tracing")]
+    pub fn set_span(&mut self, span: tracing::Span) {
+        self.span = span;
+    }
+
     pub fn is_closed(&self) -> bool {
         matches!(self.state, State::Closed)
     }
 
+    pub fn box_size(&self) -> usize {
+        self.box_size
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn padding(&self) -> Padding {
+        self.encoding.padding
+    }
+
+    // The most plaintext that goes into one box, leaving room for padding
+    // and compression.
+    fn body_capacity(&self) -> usize {
+        self.box_size - self.encoding.overhead(self.box_size)
+    }
+
+    // The biggest box that strict mode allows.
+    fn box_size_limit(&self) -> usize {
+        if self.strict {
+            MAX_BOX_SIZE
+        } else {
+            MAX_EXTENDED_BOX_SIZE
+        }
+    }
+
+    /// The number of plaintext bytes waiting to be sealed into a box.
+    pub fn buffered_len(&self) -> usize {
+        match self.state {
+            State::Buffering { pos } => pos,
+            State::Sending { buffered, .. } => buffered,
+            _ => 0,
+        }
+    }
+
+    /// The number of sealed bytes (headers included) that haven't yet been
+    /// accepted by the inner writer.
+    pub fn pending_ciphertext_len(&self) -> usize {
+        let queued: usize = self.queued.iter().map(|(_, _, n)| Head::SIZE + n).sum();
+        queued
+            + match self.state {
+                State::Sending { body_size, pos, .. } => Head::SIZE + body_size - pos,
+                State::SendingGoodbye { pos, .. } => Head::SIZE - pos,
+                State::Buffering { .. } | State::Closed => 0,
+            }
+    }
+
+    /// A snapshot of the traffic written so far.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
     pub fn into_inner(self) -> W {
         self.inner
     }
 }
 
+impl<W, const N: usize> BoxWriter<W, [u8; N]> {
+    /// Create a writer with an inline buffer, which sends boxes of up to `N` bytes.
+    /// An `N` of zero or more than 4096 is a compile-time error:
+    ///
+    /// ```compile_fail
+    /// # use ssb_boxstream::BoxWriter;
+    /// # use ssb_crypto::secretbox::{Key, Nonce};
+    /// let w = BoxWriter::<_, [u8; 5000]>::inline(Vec::<u8>::new(), Key([0; 32]), Nonce([0; 24]));
+    /// ```
+    ///
+    /// ```compile_fail
+    /// # use ssb_boxstream::BoxWriter;
+    /// # use ssb_crypto::secretbox::{Key, Nonce};
+    /// let w = BoxWriter::<_, [u8; 0]>::inline(Vec::<u8>::new(), Key([0; 32]), Nonce([0; 24]));
+    /// ```
+    pub fn inline(w: W, key: Key, nonce: Nonce) -> BoxWriter<W, [u8; N]> {
+        let () = BoxSize::<N, MAX_BOX_SIZE>::CHECK;
+        let mut w = BoxWriter::with_buffer(w, key, nonce, [0; N]);
+        w.set_box_size(N);
+        w
+    }
+
+    /// Like [`inline`](#method.inline), but `N` can be up to 65535, and strict
+    /// mode is turned off; see [`set_strict`](#method.set_strict).
+    /// Only use this with a peer that accepts extended boxes.
+    /// An `N` of zero or more than 65535 is a compile-time error:
+    ///
+    /// ```compile_fail
+    /// # use ssb_boxstream::BoxWriter;
+    /// # use ssb_crypto::secretbox::{Key, Nonce};
+    /// let w = BoxWriter::<_, [u8; 70000]>::inline_extended(Vec::<u8>::new(), Key([0; 32]), Nonce([0; 24]));
+    /// ```
+    pub fn inline_extended(w: W, key: Key, nonce: Nonce) -> BoxWriter<W, [u8; N]> {
+        let () = BoxSize::<N, MAX_EXTENDED_BOX_SIZE>::CHECK;
+        let mut w = BoxWriter::with_buffer(w, key, nonce, [0; N]);
+        w.set_strict(false);
+        w.set_box_size(N);
+        w
+    }
+}
+
+impl<W, P: BufferProvider> BoxWriter<W, Pooled<P>> {
+    /// Like [`new`](#method.new), but the buffer comes from `provider`, and is
+    /// only held while there's plaintext in it or a box is being sent. It's
+    /// zeroed before it goes back.
+    pub fn pooled(w: W, key: Key, nonce: Nonce, provider: P) -> BoxWriter<W, Pooled<P>> {
+        let buffer = Pooled::new(provider, MAX_BOX_SIZE);
+        let mut w = BoxWriter::with_sized_buffer(w, key, nonce, buffer, MAX_BOX_SIZE);
+        w.release = Pooled::release;
+        w
+    }
+}
+
 impl<W> BoxWriter<W, Vec<u8>> {
     pub fn new(w: W, key: Key, nonce: Nonce) -> BoxWriter<W, Vec<u8>> {
-        BoxWriter::with_buffer(w, key, nonce, vec![0; 4096])
+        BoxWriter::with_buffer(w, key, nonce, vec![0; MAX_BOX_SIZE])
+    }
+
+    /// Create a writer that sends boxes of up to `size` bytes.
+    /// Sizes over [`MAX_BOX_SIZE`](./constant.MAX_BOX_SIZE.html) turn strict mode off;
+    /// see [`set_strict`](#method.set_strict).
+    ///
+    /// # Panics
+    /// Panics if `size` is zero or over [`MAX_EXTENDED_BOX_SIZE`](./constant.MAX_EXTENDED_BOX_SIZE.html).
+    pub fn with_box_size(w: W, key: Key, nonce: Nonce, size: usize) -> BoxWriter<W, Vec<u8>> {
+        let mut w = BoxWriter::with_buffer(w, key, nonce, vec![0; size]);
+        w.set_strict(size <= MAX_BOX_SIZE);
+        w.set_box_size(size);
+        w
+    }
+
+    /// Create a double-buffered writer; see [`with_buffers`](#method.with_buffers).
+    pub fn double_buffered(w: W, key: Key, nonce: Nonce) -> BoxWriter<W, Vec<u8>> {
+        BoxWriter::with_buffers(w, key, nonce, vec![0; MAX_BOX_SIZE], vec![0; MAX_BOX_SIZE])
+    }
+}
+
+impl<W: fmt::Debug, B, O> fmt::Debug for BoxWriter<W, B, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The key, nonces and buffer contents are deliberately left out.
+        f.debug_struct("BoxWriter")
+            .field("inner", &self.inner)
+            .field("state", &self.state)
+            .field("box_size", &self.box_size)
+            .field("padding", &self.encoding.padding)
+            .field("boxes", &self.stats.boxes)
+            .field("flush_policy", &self.flush.policy())
+            .field("cover_traffic", &self.cover_traffic())
+            .field("closed", &self.is_closed())
+            .field("failed", &self.failed)
+            .finish_non_exhaustive()
     }
 }
 
@@ -55,14 +400,15 @@ enum State {
     Buffering {
         pos: usize,
     },
-    SendingHead {
+    // Sending a sealed box. `pos` counts the bytes of the head and body together.
+    // If the body is in the back buffer, `buffered` bytes of plaintext have
+    // been written to the front buffer in the meantime.
+    Sending {
         head: Head,
-        pos: usize,
-        body_size: usize,
-    },
-    SendingBody {
+        body: Body,
         body_size: usize,
         pos: usize,
+        buffered: usize,
     },
     SendingGoodbye {
         head: Head,
@@ -71,164 +417,608 @@ enum State {
     Closed,
 }
 
-impl<W, B> AsyncWrite for BoxWriter<W, B>
+// Where the body of the box being sent lives.
+#[derive(Clone, Copy)]
+enum Body {
+    Buffer,
+    Back,
+    Owned { start: usize },
+    Scratch,
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Buffering { pos } => f.debug_struct("Buffering").field("pos", pos).finish(),
+            State::Sending {
+                body_size,
+                pos,
+                buffered,
+                ..
+            } => f
+                .debug_struct("Sending")
+                .field("body_size", body_size)
+                .field("pos", pos)
+                .field("buffered", buffered)
+                .finish(),
+            State::SendingGoodbye { pos, .. } => {
+                f.debug_struct("SendingGoodbye").field("pos", pos).finish()
+            }
+            State::Closed => f.write_str("Closed"),
+        }
+    }
+}
+
+impl<W, B, O> BoxWriter<W, B, O>
 where
     W: AsyncWrite + Unpin + 'static,
     B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
 {
-    fn poll_write(
-        self: Pin<&mut Self>,
+    // Seal the first `body_size` buffered bytes, and start sending them.
+    fn seal_buffered(&mut self, body_size: usize) {
+        self.seal_body(Body::Buffer, body_size)
+    }
+
+    fn seal_body(&mut self, mut body: Body, mut body_size: usize) {
+        let limit = self.box_size_limit();
+        let plaintext_len = body_size;
+        let mut encoded = self.encoding.is_plain();
+        // Padding alone is done in place, if there's room for it after the body.
+        if let Some(len) = self.encoding.in_place_len(body_size, self.box_size, limit) {
+            match body {
+                Body::Buffer if len <= self.buffer_size => {
+                    pad_in_place(&mut self.buffer.as_mut()[..len], body_size);
+                    body_size = len;
+                    encoded = true;
+                }
+                Body::Owned { start }
+                    if start + body_size == self.owned.len()
+                        && start + len <= self.owned.capacity() =>
+                {
+                    pad(&mut self.owned, start, len);
+                    body_size = len;
+                    encoded = true;
+                }
+                _ => {}
+            }
+        }
+        // Whether this is the last of a caller's buffer.
+        let owned_tail =
+            matches!(body, Body::Owned { start } if start + body_size == self.owned.len());
+        let bytes = match body {
+            // An empty box doesn't need the buffer.
+            Body::Buffer if body_size == 0 => &mut [],
+            Body::Buffer => &mut self.buffer.as_mut()[..body_size],
+            Body::Back | Body::Scratch => unreachable!(),
+            Body::Owned { start } => &mut self.owned[start..start + body_size],
+        };
+        let bytes = if encoded {
+            bytes
+        } else {
+            // The plaintext is encoded into a copy, so the buffer it came from
+            // is free again as soon as it's sealed, as if it were double-buffered.
+            self.scratch.clear();
+            body_size = self
+                .encoding
+                .encode(bytes, &mut self.scratch, self.box_size, limit);
+            body = Body::Scratch;
+            if owned_tail {
+                // The rest of the caller's buffer has been copied out too, so
+                // its plaintext doesn't need to stay in the writer.
+                self.owned.fill(0);
+                self.owned = Vec::new();
+            }
+            &mut self.scratch[..]
+        };
+        let head = self.sealer.seal_head(bytes);
+        if let (Body::Buffer, Some(back)) = (body, &mut self.back) {
+            // Send from the back buffer, and keep filling the front one.
+            core::mem::swap(&mut self.buffer, back);
+            body = Body::Back;
+        }
+        self.body_sealed(plaintext_len, self.sealer.position() - 1);
+        self.state = State::Sending {
+            head,
+            body,
+            body_size,
+            pos: 0,
+            buffered: 0,
+        };
+    }
+
+    // Bookkeeping after a body has been sealed. `len` is the size of its
+    // plaintext, before padding and compression, and `nonce` is the position
+    // of its nonce in the series.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn body_sealed(&mut self, len: usize, nonce: u64) {
+        trace!(index = self.stats.boxes, len, nonce, "box sealed");
+        self.observer.on_box_sealed(self.stats.boxes, len);
+        self.stats.record_box(len);
+        self.cover.box_sealed();
+    }
+
+    // Copy as much of `bufs` as we can into the buffer, sealing and sending
+    // boxes as the buffer fills up.
+    fn poll_write_bufs(
+        &mut self,
         cx: &mut Context,
-        mut to_write: &[u8],
+        bufs: &[IoSlice],
     ) -> Poll<Result<usize, Error>> {
-        let mut this = self.get_mut();
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+
+        let mut bufs = bufs.iter().map(|b| &**b);
+        let mut to_write = bufs.next().unwrap_or(&[]);
         let mut wrote_bytes = 0;
 
         loop {
-            match this.state {
+            match self.state {
                 State::Buffering { pos } => {
-                    let buffer = this.buffer.as_mut();
-                    let n = min(buffer.len() - pos, to_write.len());
+                    let capacity = self.body_capacity();
+                    let n = min(capacity.saturating_sub(pos), to_write.len());
 
+                    // Only take the buffer when there's something to put in it.
                     let (b, rest) = to_write.split_at(n);
-                    buffer[pos..pos + n].copy_from_slice(b);
+                    if n > 0 {
+                        self.buffer.as_mut()[pos..pos + n].copy_from_slice(b);
+                    }
 
                     wrote_bytes += n;
                     to_write = rest;
 
-                    if pos + n == buffer.len() {
-                        let head = seal(buffer, &this.key, &mut this.nonces);
-                        this.state = State::SendingHead {
-                            head,
-                            pos: 0,
-                            body_size: buffer.len(),
-                        };
+                    if pos + n >= capacity {
+                        self.seal_buffered(pos + n);
                     } else {
-                        this.state = State::Buffering { pos: pos + n };
-                        return Poll::Ready(Ok(wrote_bytes));
+                        self.state = State::Buffering { pos: pos + n };
+                        match bufs.next() {
+                            Some(next) => to_write = next,
+                            None => return Poll::Ready(Ok(wrote_bytes)),
+                        }
                     }
                 }
 
-                State::SendingHead {
-                    head,
-                    pos,
-                    body_size,
-                } => {
-                    let hb = head.as_bytes();
-                    let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &hb[pos..]))?;
-                    if pos + n == hb.len() {
-                        this.state = State::SendingBody { body_size, pos: 0 };
-                    } else {
-                        this.state = State::SendingHead {
-                            head,
-                            pos: pos + n,
-                            body_size,
-                        };
-                        return Poll::Pending;
-                    }
-                }
+                State::Sending { body, .. } => match self.poll_send(cx) {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Pending if matches!(body, Body::Back | Body::Scratch) => {
+                        // Keep filling the front buffer while the back one drains.
+                        loop {
+                            let buffered = self.buffered_len();
+                            let capacity = self.body_capacity();
+                            let n = min(capacity.saturating_sub(buffered), to_write.len());
+                            let (b, rest) = to_write.split_at(n);
+                            if n > 0 {
+                                self.buffer.as_mut()[buffered..buffered + n].copy_from_slice(b);
+                            }
+                            if let State::Sending { buffered, .. } = &mut self.state {
+                                *buffered += n;
+                            }
+                            wrote_bytes += n;
+                            to_write = rest;
 
-                State::SendingBody { body_size, pos } => {
-                    let n = ready!(Pin::new(&mut this.inner)
-                        .poll_write(cx, &this.buffer.as_mut()[pos..body_size]))?;
-                    if pos + n == body_size {
-                        this.state = State::Buffering { pos: 0 };
-                    } else {
-                        this.state = State::SendingBody {
-                            body_size,
-                            pos: pos + n,
+                            match bufs.next() {
+                                Some(next) if to_write.is_empty() => to_write = next,
+                                _ => break,
+                            }
+                        }
+                        return if wrote_bytes > 0 {
+                            Poll::Ready(Ok(wrote_bytes))
+                        } else {
+                            Poll::Pending
                         };
-                        return Poll::Pending;
                     }
-                }
+                    // Any bytes accepted by this call are already sealed;
+                    // a send error will surface on the next call.
+                    _ if wrote_bytes > 0 => return Poll::Ready(Ok(wrote_bytes)),
+                    p => return p.map_ok(|()| 0),
+                },
 
-                State::SendingGoodbye { .. } => panic!(), // ??
-                State::Closed => return Poll::Ready(Ok(0)),
+                State::SendingGoodbye { .. } | State::Closed => return Poll::Ready(Ok(0)),
             }
         }
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
-        let mut this = self.get_mut();
-        match this.state {
-            State::Buffering { pos } => {
-                if pos == 0 {
-                    Pin::new(&mut this.inner).poll_flush(cx)
-                } else {
-                    let mut body = &mut this.buffer.as_mut()[..pos];
-                    let head = seal(&mut body, &this.key, &mut this.nonces);
-                    this.state = State::SendingHead {
-                        head,
-                        pos: 0,
-                        body_size: pos,
-                    };
-                    Pin::new(this).poll_flush(cx)
-                }
+    // Seal and send everything that's been buffered.
+    fn poll_send_buffered(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        loop {
+            match self.state {
+                State::Buffering { pos } if pos > 0 => self.seal_buffered(pos),
+                State::Sending { .. } => ready!(self.poll_send(cx))?,
+                _ => return Poll::Ready(Ok(())),
             }
+        }
+    }
 
-            State::SendingHead {
+    // Start sending the next queued box, if there is one.
+    fn send_queued(&mut self, buffered: usize) {
+        if let Some((head, start, body_size)) = self.queued.pop_front() {
+            self.state = State::Sending {
                 head,
-                pos,
+                body: Body::Owned { start },
                 body_size,
-            } => {
-                let bytes = head.as_bytes();
+                pos: 0,
+                buffered,
+            };
+        }
+    }
 
-                let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &bytes[pos..]))?;
-                if pos + n == bytes.len() {
-                    this.state = State::SendingBody { body_size, pos: 0 };
-                    Pin::new(this).poll_flush(cx)
+    // Write out the sealed box, if there is one, and any boxes queued after it.
+    // The heads and bodies are handed to the inner writer together, so that
+    // a writer that supports vectored writes can send them in one go.
+    fn poll_send(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        while let State::Sending {
+            head,
+            body,
+            body_size,
+            pos,
+            ..
+        } = self.state
+        {
+            let bytes = match body {
+                Body::Buffer if body_size == 0 => &[],
+                Body::Buffer => &self.buffer.as_mut()[..body_size],
+                Body::Back => &self.back.as_mut().unwrap().as_mut()[..body_size],
+                Body::Owned { start } => &self.owned[start..start + body_size],
+                Body::Scratch => &self.scratch[..body_size],
+            };
+            let n = if pos < Head::SIZE || !self.queued.is_empty() {
+                let mut bufs = [IoSlice::new(&[]); 2 * MAX_WRITE_BOXES];
+                let (head_pos, body_pos) = if pos < Head::SIZE {
+                    (pos, 0)
                 } else {
-                    this.state = State::SendingHead {
-                        head,
-                        pos: pos + n,
-                        body_size,
-                    };
-                    Poll::Pending
+                    (Head::SIZE, pos - Head::SIZE)
+                };
+                bufs[0] = IoSlice::new(&head.as_bytes()[head_pos..]);
+                bufs[1] = IoSlice::new(&bytes[body_pos..]);
+                let queued = self.queued.iter().take(MAX_WRITE_BOXES - 1);
+                for (i, (head, start, size)) in queued.enumerate() {
+                    bufs[2 * i + 2] = IoSlice::new(head.as_bytes());
+                    bufs[2 * i + 3] = IoSlice::new(&self.owned[*start..start + size]);
                 }
+                let len = 2 * min(self.queued.len() + 1, MAX_WRITE_BOXES);
+                ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, &bufs[..len]))
+            } else {
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &bytes[pos - Head::SIZE..]))
             }
+            .map_err(|err| self.inner_failed(err))?;
+            self.stats.ciphertext_bytes += n as u64;
 
-            State::SendingBody { body_size, pos } => {
-                let n =
-                    ready!(Pin::new(&mut this.inner)
-                        .poll_write(cx, &this.buffer.as_mut()[pos..body_size]))?;
-                if pos + n == body_size {
-                    this.state = State::Buffering { pos: 0 };
-                    Pin::new(&mut this.inner).poll_flush(cx)
-                } else {
-                    this.state = State::SendingBody {
-                        body_size,
-                        pos: pos + n,
-                    };
-                    Poll::Pending
+            if n == 0 {
+                return Poll::Ready(Err(self.inner_failed(ErrorKind::WriteZero.into())));
+            }
+            self.sent(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Note an error from the inner writer, for `Debug`.
+    fn inner_failed(&mut self, err: Error) -> Error {
+        self.failed = true;
+        inner_error(err)
+    }
+
+    // Mark `n` bytes as taken by the inner writer, moving on to the queued
+    // boxes as each one is finished.
+    fn sent(&mut self, mut n: usize) {
+        while let State::Sending {
+            head,
+            body,
+            body_size,
+            pos,
+            buffered,
+        } = self.state
+        {
+            let left = Head::SIZE + body_size - pos;
+            if n < left {
+                self.state = State::Sending {
+                    head,
+                    body,
+                    body_size,
+                    pos: pos + n,
+                    buffered,
+                };
+                return;
+            }
+            n -= left;
+            self.state = State::Buffering { pos: buffered };
+            match body {
+                // Let go of a caller's buffer once its last box is sent.
+                Body::Owned { start } if start + body_size == self.owned.len() => {
+                    self.owned = Vec::new();
                 }
+                // Don't leave plaintext lying around in the copy.
+                Body::Scratch => self.scratch.fill(0),
+                _ => {}
+            }
+            self.send_queued(buffered);
+            // Buffers that aren't holding anything can go back to their pool.
+            if let Some(back) = &mut self.back {
+                (self.release)(back);
+            }
+            if buffered == 0 {
+                (self.release)(&mut self.buffer);
+            }
+            if n == 0 {
+                return;
             }
-            State::SendingGoodbye { .. } => panic!(),
-            State::Closed => Poll::Ready(Ok(())),
         }
     }
+}
+
+impl<W, B, O> BoxWriter<W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Write all of `buf`, sealing it in place instead of copying it into the
+    /// writer's buffer first. Anything already buffered is sent as its own box,
+    /// then `buf` is sent in full-size boxes. Like `write_all`, this doesn't flush
+    /// the inner writer.
+    ///
+    /// If the returned future is dropped before it completes, the box that's
+    /// being sent will still be sent by the next write or flush, but the rest
+    /// of `buf` won't be.
+    pub fn write_owned(&mut self, buf: Vec<u8>) -> WriteOwned<'_, W, B, O> {
+        WriteOwned {
+            writer: self,
+            len: buf.len(),
+            buf: Some(buf),
+            pos: 0,
+        }
+    }
+
+    /// Send `buf` as exactly one box. Anything already buffered is sent as its
+    /// own box first, so the box holds `buf` and nothing else. Like `write_owned`,
+    /// this doesn't flush the inner writer.
+    ///
+    /// Fails with `InvalidInput`, without sending anything, if `buf` is bigger than
+    /// [`MAX_BOX_SIZE`](./constant.MAX_BOX_SIZE.html) (or, with strict mode off,
+    /// [`MAX_EXTENDED_BOX_SIZE`](./constant.MAX_EXTENDED_BOX_SIZE.html)), less the
+    /// room that padding and compression need when they're on. An empty `buf`
+    /// is sent as an empty box.
+    pub fn write_box<'a>(&'a mut self, buf: &'a [u8]) -> WriteBox<'a, W, B, O> {
+        WriteBox {
+            writer: self,
+            buf,
+            sealed: false,
+        }
+    }
+}
+
+/// Future returned by [`BoxWriter::write_box`](./struct.BoxWriter.html#method.write_box).
+pub struct WriteBox<'a, W, B, O> {
+    writer: &'a mut BoxWriter<W, B, O>,
+    buf: &'a [u8],
+    sealed: bool,
+}
+
+impl<W, B, O> Future for WriteBox<'_, W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let w = &mut *this.writer;
+        #[cfg(feature = "tracing")]
+        let _span = w.span.clone().entered();
+
+        if let Some(err) = w.flush.take_error() {
+            return Poll::Ready(Err(err));
+        }
+        let limit = w.box_size_limit() - w.encoding.overhead(w.box_size_limit());
+        if this.buf.len() > limit {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "box of {} bytes is over the limit of {}",
+                    this.buf.len(),
+                    limit
+                ),
+            )));
+        }
+        loop {
+            if matches!(w.state, State::SendingGoodbye { .. } | State::Closed) {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            ready!(w.poll_send_buffered(cx))?;
+            if this.sealed {
+                return Poll::Ready(Ok(()));
+            }
+
+            // The body is sealed in place, so it needs a copy of its own, with
+            // room to pad it. The copy is dropped once it's been sent.
+            let len = this.buf.len();
+            let padded = w.encoding.in_place_len(len, w.box_size, w.box_size_limit());
+            w.owned = Vec::with_capacity(padded.unwrap_or(len));
+            w.owned.extend_from_slice(this.buf);
+            w.seal_body(Body::Owned { start: 0 }, this.buf.len());
+            this.sealed = true;
+        }
+    }
+}
+
+/// Future returned by [`BoxWriter::write_owned`](./struct.BoxWriter.html#method.write_owned).
+pub struct WriteOwned<'a, W, B, O> {
+    writer: &'a mut BoxWriter<W, B, O>,
+    // `None` while the buffer is lent to the writer.
+    buf: Option<Vec<u8>>,
+    len: usize,
+    pos: usize,
+}
+
+impl<W, B, O> Future for WriteOwned<'_, W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let w = &mut *this.writer;
+        #[cfg(feature = "tracing")]
+        let _span = w.span.clone().entered();
+
+        if let Some(err) = w.flush.take_error() {
+            return Poll::Ready(Err(err));
+        }
+        loop {
+            if matches!(w.state, State::SendingGoodbye { .. } | State::Closed) {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            ready!(w.poll_send_buffered(cx))?;
+
+            if this.pos == this.len {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(buf) = this.buf.take() {
+                w.owned = buf;
+            }
+
+            let body_size = min(w.body_capacity(), this.len - this.pos);
+            w.seal_body(Body::Owned { start: this.pos }, body_size);
+            this.pos += body_size;
+        }
+    }
+}
+
+impl<W, B, O> AsyncWrite for BoxWriter<W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.get_mut()
+            .poll_write_with_policy(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<Result<usize, Error>> {
+        self.get_mut().poll_write_with_policy(cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        #[cfg(feature = "tracing")]
+        let _span = this.span.clone().entered();
+
+        if let Some(err) = this.flush.take_error() {
+            return Poll::Ready(Err(err));
+        }
+        if matches!(this.state, State::SendingGoodbye { .. } | State::Closed) {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(this.poll_send_buffered(cx))?;
+        ready!(Pin::new(&mut this.inner).poll_flush(cx)).map_err(|err| this.inner_failed(err))?;
+        this.stats.flushes += 1;
+        this.flush.flushed();
+        Poll::Ready(Ok(()))
+    }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
         let mut this = self.get_mut();
-        match this.state {
-            State::SendingGoodbye { head, pos } => {
-                let bytes = head.as_bytes();
-
-                let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &bytes[pos..]))?;
-                if pos + n == bytes.len() {
-                    this.state = State::Closed;
-                    Pin::new(&mut this.inner).poll_close(cx)
-                } else {
-                    this.state = State::SendingGoodbye { head, pos: pos + n };
-                    Poll::Pending
+        #[cfg(feature = "tracing")]
+        let _span = this.span.clone().entered();
+
+        loop {
+            match this.state {
+                State::SendingGoodbye { head, pos } => {
+                    let bytes = head.as_bytes();
+
+                    let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &bytes[pos..]))
+                        .map_err(|err| this.inner_failed(err))?;
+                    this.stats.ciphertext_bytes += n as u64;
+                    if n == 0 {
+                        return Poll::Ready(Err(this.inner_failed(ErrorKind::WriteZero.into())));
+                    }
+                    if pos + n == bytes.len() {
+                        debug!(boxes = this.stats.boxes, "goodbye sent");
+                        this.observer.on_goodbye_sent();
+                        this.stats.goodbye = true;
+                        this.state = State::Closed;
+                    } else {
+                        this.state = State::SendingGoodbye { head, pos: pos + n };
+                    }
                 }
-            }
 
-            _ => {
-                ready!(Pin::new(&mut this).poll_flush(cx))?;
-                let head = HeadPayload::goodbye().seal(&this.key, this.nonces.next());
-                this.state = State::SendingGoodbye { head, pos: 0 };
-                Pin::new(&mut this).poll_close(cx)
+                State::Closed => {
+                    return Pin::new(&mut this.inner)
+                        .poll_close(cx)
+                        .map_err(|err| this.inner_failed(err))
+                }
+
+                _ => {
+                    ready!(Pin::new(&mut this).poll_flush(cx))?;
+                    let head = this.sealer.goodbye_head();
+                    this.state = State::SendingGoodbye { head, pos: 0 };
+                }
             }
         }
     }
 }
+
+#[test]
+fn owned_buffer_is_released() {
+    use futures_executor::block_on;
+
+    let mut w = BoxWriter::new(Vec::new(), Key([1; 32]), Nonce([2; 24]));
+    block_on(w.write_owned(vec![3; 10_000])).unwrap();
+    assert_eq!(w.owned.capacity(), 0);
+    block_on(w.write_box(&[3; 4000])).unwrap();
+    assert_eq!(w.owned.capacity(), 0);
+}
+
+#[test]
+fn padded_in_place() {
+    use futures_executor::block_on;
+    use futures_util::io::AsyncWriteExt;
+
+    let mut w = BoxWriter::new(Vec::new(), Key([1; 32]), Nonce([2; 24]));
+    w.set_padding(Padding::Bucket(100));
+    block_on(async {
+        w.write_all(&[3; 10]).await.unwrap();
+        w.flush().await.unwrap();
+        w.write_box(&[3; 10]).await.unwrap();
+    });
+    assert_eq!(w.scratch.capacity(), 0);
+    assert_eq!(w.stats().ciphertext_bytes, 2 * (34 + 100));
+
+    // Bodies that can't be padded where they are go through a copy, which is
+    // zeroed once it's sent, and the caller's buffer is let go.
+    block_on(w.write_owned(vec![3; 5000])).unwrap();
+    assert!(!w.scratch.is_empty());
+    assert!(w.scratch.iter().all(|&b| b == 0));
+    assert_eq!(w.owned.capacity(), 0);
+    block_on(w.write_box(&[3; 4000])).unwrap();
+    assert_eq!(w.owned.capacity(), 0);
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+fn compressed_owned_is_released() {
+    use crate::compress::Compression;
+    use futures_executor::block_on;
+
+    let mut w = BoxWriter::new(Vec::new(), Key([1; 32]), Nonce([2; 24]));
+    w.set_compression(Compression::Deflate(6));
+    block_on(w.write_owned(vec![3; 10_000])).unwrap();
+    assert_eq!(w.owned.capacity(), 0);
+    assert!(w.scratch.iter().all(|&b| b == 0));
+    block_on(w.write_box(&[3; 1000])).unwrap();
+    assert_eq!(w.owned.capacity(), 0);
+    assert_eq!(w.stats().plaintext_bytes, 11_000);
+}
@@ -0,0 +1,48 @@
+use super::*;
+use ::bytes::{Buf, Bytes};
+
+impl<W, B, O> BoxWriter<W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Like [`write_owned`](#method.write_owned), for `Bytes`. If `bytes` is the
+    /// only handle to its memory, it's sealed in place without being copied.
+    pub fn write_bytes(&mut self, bytes: Bytes) -> WriteOwned<'_, W, B, O> {
+        self.write_owned(Vec::from(bytes))
+    }
+
+    /// Write all of the remaining bytes of `buf`, like `write_all`.
+    pub fn write_buf<T: Buf + Unpin>(&mut self, buf: T) -> WriteBuf<'_, W, B, O, T> {
+        WriteBuf { writer: self, buf }
+    }
+}
+
+/// Future returned by [`BoxWriter::write_buf`](./struct.BoxWriter.html#method.write_buf).
+pub struct WriteBuf<'a, W, B, O, T> {
+    writer: &'a mut BoxWriter<W, B, O>,
+    buf: T,
+}
+
+impl<W, B, O, T> Future for WriteBuf<'_, W, B, O, T>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+    T: Buf + Unpin,
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        while this.buf.has_remaining() {
+            let n = ready!(Pin::new(&mut *this.writer).poll_write(cx, this.buf.chunk()))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            this.buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
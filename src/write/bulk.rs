@@ -0,0 +1,146 @@
+use super::*;
+use crate::codec::seal_with;
+use crate::padding::pad_in_place;
+use rayon::prelude::*;
+
+impl<W, B, O> BoxWriter<W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Like [`write_owned`](#method.write_owned), but all of the boxes are sealed
+    /// up front, in parallel on the rayon thread pool. The bytes sent are exactly
+    /// the same as with `write_owned`.
+    ///
+    /// The boxes are queued in the writer once they're sealed, so if the
+    /// returned future is dropped before it completes, they're still sent by
+    /// the next write or flush.
+    ///
+    /// Queued boxes are handed to the inner writer up to 32 at a time, so an
+    /// inner writer that supports vectored writes, like a socket, can take
+    /// several of them in one `writev`. Other writes hand it one box at a time.
+    pub fn write_bulk(&mut self, buf: Vec<u8>) -> WriteBulk<'_, W, B, O> {
+        WriteBulk {
+            writer: self,
+            buf: Some(buf),
+        }
+    }
+}
+
+impl<W, B, O> BoxWriter<W, B, O> {
+    // Pad the bodies in `owned` where they are, moving them apart to make room.
+    fn pad_owned_in_place(&mut self, bodies: &mut [(usize, usize)], limit: usize) {
+        let padded: Vec<usize> = bodies
+            .iter()
+            .map(|(_, n)| {
+                self.encoding
+                    .in_place_len(*n, self.box_size, limit)
+                    .unwrap()
+            })
+            .collect();
+        self.owned.resize(padded.iter().sum(), 0);
+        // Bodies only move towards the back, so working from the last one,
+        // none is overwritten before it's been moved.
+        let mut end = self.owned.len();
+        for ((start, size), padded) in bodies.iter_mut().zip(padded).rev() {
+            end -= padded;
+            self.owned.copy_within(*start..*start + *size, end);
+            pad_in_place(&mut self.owned[end..end + padded], *size);
+            *start = end;
+            *size = padded;
+        }
+    }
+}
+
+/// Future returned by [`BoxWriter::write_bulk`](./struct.BoxWriter.html#method.write_bulk).
+pub struct WriteBulk<'a, W, B, O> {
+    writer: &'a mut BoxWriter<W, B, O>,
+    // `None` once it's been sealed and handed to the writer.
+    buf: Option<Vec<u8>>,
+}
+
+impl<W, B, O> Future for WriteBulk<'_, W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let w = &mut *this.writer;
+        #[cfg(feature = "tracing")]
+        let _span = w.span.clone().entered();
+
+        if let Some(err) = w.flush.take_error() {
+            return Poll::Ready(Err(err));
+        }
+        loop {
+            if matches!(w.state, State::SendingGoodbye { .. } | State::Closed) {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            ready!(w.poll_send_buffered(cx))?;
+
+            let buf = match this.buf.take() {
+                Some(buf) => buf,
+                None => return Poll::Ready(Ok(())),
+            };
+            let capacity = w.body_capacity();
+            let limit = w.box_size_limit();
+            // Split `buf` into bodies, encoding each one into a copy if need be.
+            // Compression carries on from one body to the next, so this
+            // part isn't done in parallel.
+            let mut bodies: Vec<(usize, usize)> = Vec::new();
+            let lens: Vec<usize> = buf.chunks(capacity).map(<[u8]>::len).collect();
+            let padded = w.encoding.in_place_len(0, w.box_size, limit).is_some();
+            if w.encoding.is_plain() || padded {
+                for chunk in buf.chunks(capacity) {
+                    let start = bodies.last().map_or(0, |(s, n)| s + n);
+                    bodies.push((start, chunk.len()));
+                }
+                w.owned = buf;
+                if padded {
+                    w.pad_owned_in_place(&mut bodies, limit);
+                }
+            } else {
+                let mut owned = Vec::new();
+                for chunk in buf.chunks(capacity) {
+                    let start = owned.len();
+                    let size = w.encoding.encode(chunk, &mut owned, w.box_size, limit);
+                    bodies.push((start, size));
+                }
+                w.owned = owned;
+            }
+
+            let nonces: Vec<(Nonce, Nonce)> =
+                bodies.iter().map(|_| w.sealer.next_nonces()).collect();
+            let mut rest = &mut w.owned[..];
+            let mut slices = Vec::with_capacity(bodies.len());
+            for (_, size) in &bodies {
+                let (body, tail) = rest.split_at_mut(*size);
+                slices.push(body);
+                rest = tail;
+            }
+            let key = w.sealer.key();
+            let heads: Vec<Head> = slices
+                .into_par_iter()
+                .zip(nonces.par_iter())
+                .map(|(body, (hn, bn))| seal_with(body, key, *hn, *bn))
+                .collect();
+
+            let first = w.sealer.position() - 2 * nonces.len() as u64;
+            for (i, len) in lens.into_iter().enumerate() {
+                w.body_sealed(len, first + 2 * i as u64 + 1);
+            }
+            w.queued.extend(
+                heads
+                    .into_iter()
+                    .zip(bodies)
+                    .map(|(head, (start, size))| (head, start, size)),
+            );
+            w.send_queued(0);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use super::*;
+use crate::timer::Sleep;
+use core::task::Waker;
+use core::time::Duration;
+
+/// When a [`BoxWriter`](./struct.BoxWriter.html) sends cover traffic: empty boxes
+/// that keep the connection looking busy while nothing is being written.
+///
+/// Cover boxes are authenticated like any other, and this crate's reader skips
+/// them just as it skips any empty box. **Only use this with a peer whose reader
+/// does the same.** Readers from ssb-boxstream 0.2 and earlier take an empty box
+/// for the end of the stream, so they'd stop at the first cover box. With
+/// [`Padding`](./enum.Padding.html) on, cover boxes are padded like the rest, so
+/// they can't be told apart from real boxes by their size either.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoverTraffic {
+    #[default]
+    Off,
+    /// Send a cover box whenever no box has been sent for this long.
+    Idle(Duration),
+    /// Send a cover box this often, whether or not other boxes are being sent.
+    Every(Duration),
+}
+
+#[derive(Default)]
+pub(super) struct Cover {
+    policy: CoverTraffic,
+    // Running until the next cover box is due.
+    sleep: Option<Sleep>,
+    // A cover box is due, but hasn't been sealed yet.
+    due: bool,
+    // A cover box has been sealed, and is being sent and flushed.
+    sending: bool,
+    // The last task to wait in `poll_cover_traffic`.
+    waker: Option<Waker>,
+}
+
+impl Cover {
+    // A box has been sealed, which restarts the wait under the `Idle` policy.
+    pub(super) fn box_sealed(&mut self) {
+        if let CoverTraffic::Idle(_) = self.policy {
+            self.reset_sleep();
+        }
+    }
+
+    // The writer is closing, so there's nothing more to send.
+    fn stop(&mut self) -> Error {
+        self.due = false;
+        self.sending = false;
+        self.sleep = None;
+        self.waker = None;
+        ErrorKind::WriteZero.into()
+    }
+
+    // Start waiting afresh, the next time `poll_cover_traffic` is polled.
+    pub(super) fn reset_sleep(&mut self) {
+        self.sleep = None;
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+impl<W, B, O> BoxWriter<W, B, O> {
+    /// Set when the writer sends cover traffic. The default is
+    /// [`CoverTraffic::Off`](./enum.CoverTraffic.html#variant.Off).
+    /// Cover boxes are only sent while something polls
+    /// [`poll_cover_traffic`](#method.poll_cover_traffic).
+    ///
+    /// # Panics
+    /// Panics if the policy isn't `Off` and no timer has been set; see
+    /// [`set_timer`](#method.set_timer).
+    pub fn set_cover_traffic(&mut self, policy: CoverTraffic) {
+        if policy != CoverTraffic::Off {
+            assert!(self.flush.timer().is_some(), "cover traffic needs a timer");
+        }
+        self.cover.policy = policy;
+        self.cover.reset_sleep();
+    }
+
+    pub fn cover_traffic(&self) -> CoverTraffic {
+        self.cover.policy
+    }
+}
+
+impl<W, B, O> BoxWriter<W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Send cover boxes as the cover traffic policy calls for. Resolves when one
+    /// has been sent and the inner writer flushed (or either failed), and is
+    /// pending in the meantime; poll it alongside the stream's other work, in a
+    /// `select!` loop for example.
+    ///
+    /// A cover box goes out right after any box that's being sent. Buffered
+    /// plaintext stays buffered, to be sent on the next flush as usual.
+    ///
+    /// Once the writer is closing or closed, this fails with `WriteZero`, and
+    /// no more cover boxes are scheduled.
+    pub fn poll_cover_traffic(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+
+        if matches!(self.state, State::SendingGoodbye { .. } | State::Closed) {
+            return Poll::Ready(Err(self.cover.stop()));
+        }
+        if !self.cover.due && !self.cover.sending {
+            let interval = match self.cover.policy {
+                CoverTraffic::Off => {
+                    self.cover.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                CoverTraffic::Idle(d) | CoverTraffic::Every(d) => d,
+            };
+            let sleep = match &mut self.cover.sleep {
+                Some(sleep) => sleep,
+                None => {
+                    let timer = self.flush.timer().unwrap();
+                    self.cover.sleep.insert(timer.sleep(interval))
+                }
+            };
+            self.cover.waker = Some(cx.waker().clone());
+            ready!(sleep.as_mut().poll(cx));
+            self.cover.sleep = None;
+            self.cover.due = true;
+        }
+
+        if self.cover.due {
+            ready!(self.poll_send(cx))?;
+            let pos = match self.state {
+                State::Buffering { pos } => pos,
+                // Nothing more can be sent once the goodbye is on its way.
+                _ => return Poll::Ready(Err(self.cover.stop())),
+            };
+            trace!(buffered = pos, "sending cover box");
+            self.seal_body(Body::Owned { start: 0 }, 0);
+            if let State::Sending { buffered, .. } = &mut self.state {
+                *buffered = pos;
+            }
+            self.cover.due = false;
+            self.cover.sending = true;
+        }
+
+        ready!(self.poll_send(cx))?;
+        ready!(Pin::new(&mut self.inner).poll_flush(cx)).map_err(inner_error)?;
+        self.cover.sending = false;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,129 @@
+use super::*;
+#[cfg(feature = "flate2")]
+use crate::compress::{max_overhead, Compression, Deflater};
+use crate::padding::pad;
+
+// What's done to a body's plaintext before it's sealed.
+#[derive(Default)]
+pub(super) struct Encoding {
+    pub(super) padding: Padding,
+    #[cfg(feature = "flate2")]
+    compression: Compression,
+    #[cfg(feature = "flate2")]
+    deflater: Option<Deflater>,
+}
+
+impl Encoding {
+    // Whether bodies are sealed exactly as they were written.
+    pub(super) fn is_plain(&self) -> bool {
+        #[cfg(feature = "flate2")]
+        if self.deflater.is_some() {
+            return false;
+        }
+        self.padding == Padding::Off
+    }
+
+    // The padded size of a body of `len` bytes, if padding is all that's done
+    // to bodies, so that it can be done in place.
+    pub(super) fn in_place_len(&self, len: usize, box_size: usize, limit: usize) -> Option<usize> {
+        #[cfg(feature = "flate2")]
+        if self.deflater.is_some() {
+            return None;
+        }
+        match self.padding {
+            Padding::Off => None,
+            padding => Some(padding.padded_len(len, box_size, limit)),
+        }
+    }
+
+    // The most that encoding can add to a body of `len` bytes.
+    pub(super) fn overhead(&self, len: usize) -> usize {
+        self.padding.overhead() + self.compression_overhead(len)
+    }
+
+    // The most that compression alone can add.
+    #[cfg_attr(not(feature = "flate2"), allow(unused_variables))]
+    pub(super) fn compression_overhead(&self, len: usize) -> usize {
+        #[cfg(feature = "flate2")]
+        if self.deflater.is_some() {
+            return max_overhead(len);
+        }
+        0
+    }
+
+    // Append the encoded `body` to `out`, and return its size.
+    // `limit` is the biggest that a body can be.
+    pub(super) fn encode(
+        &mut self,
+        body: &[u8],
+        out: &mut Vec<u8>,
+        box_size: usize,
+        limit: usize,
+    ) -> usize {
+        let start = out.len();
+        #[cfg(feature = "flate2")]
+        match &mut self.deflater {
+            // An empty body stays empty, so the peer still skips it.
+            Some(deflater) if !body.is_empty() => deflater.deflate(body, out),
+            _ => out.extend_from_slice(body),
+        }
+        #[cfg(not(feature = "flate2"))]
+        out.extend_from_slice(body);
+        if self.padding != Padding::Off {
+            let len = self.padding.padded_len(out.len() - start, box_size, limit);
+            pad(out, start, len);
+        }
+        // The body capacity leaves room for the most that encoding can add,
+        // so this only fails if that's been worked out wrong.
+        let len = out.len() - start;
+        assert!(
+            len <= limit,
+            "encoded body of {} bytes is over the limit of {}",
+            len,
+            limit
+        );
+        len
+    }
+}
+
+#[cfg(feature = "flate2")]
+impl<W, B: AsMut<[u8]>, O> BoxWriter<W, B, O> {
+    /// Compress the bodies of boxes. **Only use this with a peer that
+    /// decompresses them**; see [`Compression`](./enum.Compression.html).
+    /// Each box then carries a little less plaintext than the box size, to
+    /// leave room for data that doesn't compress.
+    ///
+    /// **Don't compress a stream that mixes secrets with data an attacker can
+    /// influence.** Box sizes show how well the data compressed, which lets an
+    /// attacker who can see them confirm guesses at a secret, as in the CRIME
+    /// and BREACH attacks. The deflate dictionary carries over from box to box,
+    /// so this applies to secrets sent in any earlier box, not just the same one.
+    ///
+    /// # Panics
+    /// Panics if the level is over 9, if the box size leaves no room for
+    /// compression, or if a box has already been sealed: the peer has to
+    /// start decompressing from the first box.
+    pub fn set_compression(&mut self, compression: Compression) {
+        if let Compression::Deflate(level) = compression {
+            assert!(level <= 9, "deflate level {} is over 9", level);
+            assert!(
+                self.box_size > self.encoding.padding.overhead() + max_overhead(self.box_size),
+                "box size {} leaves no room for compression",
+                self.box_size
+            );
+        }
+        assert!(
+            self.stats.boxes == 0,
+            "compression must be set before the first box"
+        );
+        self.encoding.compression = compression;
+        self.encoding.deflater = match compression {
+            Compression::Off => None,
+            Compression::Deflate(level) => Some(Deflater::new(level)),
+        };
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.encoding.compression
+    }
+}
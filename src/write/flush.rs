@@ -0,0 +1,179 @@
+use super::*;
+use crate::timer::{Sleep, Timer};
+use core::task::Waker;
+use core::time::Duration;
+
+/// When a [`BoxWriter`](./struct.BoxWriter.html) flushes on its own.
+///
+/// Full boxes are always sent as soon as the buffer fills up; the policy
+/// decides what happens to a partly-filled buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only flush when `flush` is called.
+    #[default]
+    Manual,
+    /// Flush after every write.
+    EveryWrite,
+    /// Flush after a write that leaves at least this many bytes buffered.
+    Threshold(usize),
+    /// Flush once written bytes have been buffered for this long.
+    /// Needs a timer, which has to be set first: setting this policy on a writer
+    /// without one panics. See [`set_timer`](./struct.BoxWriter.html#method.set_timer).
+    Delay(Duration),
+}
+
+#[derive(Default)]
+pub(super) struct AutoFlush {
+    policy: FlushPolicy,
+    timer: Option<Box<dyn Timer + Send + Sync>>,
+    // Running while there's buffered data, under the `Delay` policy.
+    sleep: Option<Sleep>,
+    // The policy called for a flush that hasn't completed yet.
+    due: bool,
+    // The last task to wait in `poll_auto_flush`.
+    waker: Option<Waker>,
+    // A flush started by a write failed; the next write or flush returns this.
+    error: Option<Error>,
+}
+
+impl AutoFlush {
+    pub(super) fn policy(&self) -> FlushPolicy {
+        self.policy
+    }
+
+    pub(super) fn timer(&self) -> Option<&(dyn Timer + Send + Sync)> {
+        self.timer.as_deref()
+    }
+
+    pub(super) fn flushed(&mut self) {
+        self.due = false;
+        self.sleep = None;
+    }
+
+    pub(super) fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    fn set_due(&mut self) {
+        self.due = true;
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+impl<W, B, O> BoxWriter<W, B, O> {
+    /// Set when the writer flushes without being asked to. The default is
+    /// [`FlushPolicy::Manual`](./enum.FlushPolicy.html#variant.Manual).
+    ///
+    /// A flush that can't complete right away is finished by the next write,
+    /// or by [`poll_auto_flush`](#method.poll_auto_flush). If it fails, the
+    /// next write or flush returns the error.
+    ///
+    /// # Panics
+    /// Panics if the policy is `Delay` and no timer has been set. Call
+    /// [`set_timer`](#method.set_timer) first.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        if let FlushPolicy::Delay(_) = policy {
+            assert!(
+                self.flush.timer.is_some(),
+                "FlushPolicy::Delay needs a timer"
+            );
+        }
+        self.flush.policy = policy;
+        self.flush.sleep = None;
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush.policy
+    }
+
+    /// Set the timer used by the [`Delay`](./enum.FlushPolicy.html#variant.Delay)
+    /// flush policy and by [cover traffic](#method.set_cover_traffic).
+    pub fn set_timer<T: Timer + Send + Sync + 'static>(&mut self, timer: T) {
+        self.flush.timer = Some(Box::new(timer));
+        self.flush.sleep = None;
+        self.cover.reset_sleep();
+    }
+}
+
+impl<W, B, O> BoxWriter<W, B, O>
+where
+    W: AsyncWrite + Unpin + 'static,
+    B: AsMut<[u8]> + Unpin,
+    O: BoxObserver + Unpin,
+{
+    /// Carry out the flushes called for by the flush policy. Resolves when
+    /// one has completed (or failed), and is pending while there's nothing to do.
+    ///
+    /// Under the `Delay` policy, a writer that isn't written to again only
+    /// flushes if something polls this; in a `select!` loop, for example.
+    pub fn poll_auto_flush(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        if !self.flush.due {
+            match &mut self.flush.sleep {
+                Some(sleep) => {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.flush.sleep = None;
+                    self.flush.due = true;
+                }
+                None => {
+                    self.flush.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+        Pin::new(self).poll_flush(cx)
+    }
+
+    pub(super) fn poll_write_with_policy(
+        &mut self,
+        cx: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<Result<usize, Error>> {
+        if let Some(err) = self.flush.take_error() {
+            return Poll::Ready(Err(err));
+        }
+        if self.flush.due {
+            ready!(Pin::new(&mut *self).poll_flush(cx))?;
+        }
+        let n = ready!(self.poll_write_bufs(cx, bufs))?;
+        if n == 0 {
+            return Poll::Ready(Ok(0));
+        }
+
+        match self.flush.policy {
+            FlushPolicy::Manual => {}
+            FlushPolicy::EveryWrite => self.flush.set_due(),
+            FlushPolicy::Threshold(t) => {
+                if self.buffered_len() >= t {
+                    self.flush.set_due();
+                }
+            }
+            FlushPolicy::Delay(delay) => {
+                if self.flush.sleep.is_none() && self.buffered_len() > 0 {
+                    let timer = self.flush.timer.as_ref().unwrap();
+                    self.flush.sleep = Some(timer.sleep(delay));
+                    if let Some(w) = self.flush.waker.take() {
+                        w.wake();
+                    }
+                }
+                if let Some(sleep) = &mut self.flush.sleep {
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        self.flush.sleep = None;
+                        self.flush.set_due();
+                    }
+                }
+            }
+        }
+
+        // The bytes have been accepted either way; if the flush can't finish
+        // now, the next write or `poll_auto_flush` picks it up. If it fails,
+        // the error is kept for the next write or flush.
+        if self.flush.due {
+            if let Poll::Ready(Err(err)) = Pin::new(&mut *self).poll_flush(cx) {
+                self.flush.error = Some(err);
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}